@@ -0,0 +1,114 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use h3o::{LatLng, Resolution};
+use hextree::{Cell, HexTreeMap};
+use lwreg::RegionMap;
+use std::io::Cursor;
+
+const LUT: [&str; 4] = ["a", "b", "c", "d"];
+
+/// Four regions splitting a few thousand res-9 cells between them.
+fn build_map() -> (HexTreeMap<u16>, Vec<Cell>) {
+    let center = LatLng::new(37.77, -122.42)
+        .unwrap()
+        .to_cell(Resolution::Nine);
+    let cells: Vec<Cell> = center
+        .grid_disk::<Vec<_>>(30)
+        .into_iter()
+        .map(|cell| Cell::from_raw(cell.into()).unwrap())
+        .collect();
+    let mut map = HexTreeMap::new();
+    for (n, &cell) in cells.iter().enumerate() {
+        map.insert(cell, (n % 4) as u16);
+    }
+    (map, cells)
+}
+
+/// [`build_map`]'s map, written out.
+fn build() -> (Vec<u8>, Vec<Cell>) {
+    let (map, cells) = build_map();
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Nine),
+        &map,
+        &LUT,
+        false,
+        None,
+    )
+    .unwrap();
+    (buf, cells)
+}
+
+fn lookup(c: &mut Criterion) {
+    let (buf, cells) = build();
+    let mut region_map =
+        RegionMap::from_readers(Cursor::new(buf.clone()), Cursor::new(buf.clone())).unwrap();
+    let mut cycle = cells.iter().cycle();
+    c.bench_function("lookup_index", |b| {
+        b.iter(|| {
+            region_map
+                .lookup_index(black_box(*cycle.next().unwrap()))
+                .unwrap()
+        })
+    });
+    c.bench_function("lookup", |b| {
+        b.iter(|| {
+            region_map
+                .lookup(black_box(*cycle.next().unwrap()))
+                .unwrap()
+        })
+    });
+
+    let path = std::env::temp_dir().join(format!("lwreg-bench-{}.disktree", std::process::id()));
+    std::fs::write(&path, &buf).unwrap();
+    c.bench_function("open_and_lookup", |b| {
+        b.iter(|| {
+            let mut region_map = RegionMap::open(&path).unwrap();
+            region_map.lookup(black_box(cells[0])).unwrap()
+        })
+    });
+
+    // The mapped path, to compare with the reader-backed one above.
+    // Nothing modifies the file while it's mapped.
+    let mut region_map = unsafe { RegionMap::open_mmap(&path) }.unwrap();
+    c.bench_function("lookup_mmap", |b| {
+        b.iter(|| {
+            region_map
+                .lookup(black_box(*cycle.next().unwrap()))
+                .unwrap()
+        })
+    });
+    c.bench_function("open_mmap_and_lookup", |b| {
+        b.iter(|| {
+            let mut region_map = unsafe { RegionMap::open_mmap(&path) }.unwrap();
+            region_map.lookup(black_box(cells[0])).unwrap()
+        })
+    });
+    drop(region_map);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Writes to a file, where each of the disktree's many small writes
+/// would be a syscall without the writer's buffer.
+fn write(c: &mut Criterion) {
+    let (map, _) = build_map();
+    let path = std::env::temp_dir().join(format!("lwreg-bench-write-{}", std::process::id()));
+    c.bench_function("write_to_file", |b| {
+        b.iter(|| {
+            let file = std::fs::File::create(&path).unwrap();
+            lwreg::write_region_map(
+                file,
+                Some(Resolution::Nine),
+                black_box(&map),
+                &LUT,
+                false,
+                None,
+            )
+            .unwrap()
+        })
+    });
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, lookup, write);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+//! Records the h3o and hextree versions the crate is built against, so
+//! that maps can say which versions wrote them.
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    // Cargo.lock is at the root of the workspace being built, which is
+    // this crate's directory or one of its parents. Built as someone
+    // else's dependency there may be none to find, and the versions
+    // are recorded as unknown.
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").expect("set by cargo"));
+    let lock_path = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.exists());
+    if let Some(path) = &lock_path {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+    let lock = lock_path
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    for (name, var) in [
+        ("h3o", "LWREG_H3O_VERSION"),
+        ("hextree", "LWREG_HEXTREE_VERSION"),
+    ] {
+        println!("cargo:rustc-env={var}={}", locked_version(&lock, name));
+    }
+}
+
+/// Returns `name`'s version in `lock`, plus the commit for a git
+/// dependency, or "unknown".
+fn locked_version(lock: &str, name: &str) -> String {
+    for package in lock.split("[[package]]") {
+        let field = |key: &str| {
+            package.lines().find_map(|line| {
+                line.strip_prefix(key)?
+                    .strip_prefix(" = \"")?
+                    .strip_suffix('"')
+            })
+        };
+        if field("name") != Some(name) {
+            continue;
+        }
+        let version = field("version").unwrap_or("unknown");
+        let commit = field("source")
+            .filter(|source| source.starts_with("git+"))
+            .and_then(|source| source.rsplit_once('#'))
+            .map(|(_, commit)| &commit[..commit.len().min(12)]);
+        return match commit {
+            Some(commit) => format!("{version}+{commit}"),
+            None => version.to_owned(),
+        };
+    }
+    "unknown".to_owned()
+}
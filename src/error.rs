@@ -0,0 +1,109 @@
+use h3o::{error::CompactionError, CellIndex};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the library.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("not an lwreg file")]
+    BadMagic,
+
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u8),
+
+    /// The file's structure is inconsistent, e.g. a truncated file.
+    #[error("corrupt lwreg file: {0}")]
+    Corrupt(String),
+
+    #[error("LuT checksum mismatch: expected {expected:08x}, found {found:08x}")]
+    LutChecksum { expected: u32, found: u32 },
+
+    #[error("failed to serialize LuT: {0}")]
+    LutSerialize(bincode::Error),
+
+    #[error("failed to deserialize LuT: {0}")]
+    LutDeserialize(bincode::Error),
+
+    /// A stored value points past the end of the LuT. Files written
+    /// by this crate never contain one, so this means corruption or a
+    /// file from a mismatched writer.
+    #[error("LuT index {idx} out of range for a LuT of {len} entries")]
+    LutIndex { idx: u32, len: usize },
+
+    /// The map was written by `write_map`, so its values can't be
+    /// resolved through a LuT.
+    #[error("map stores raw values and has no LuT")]
+    NoLut,
+
+    /// The map was written by `write_region_map`, so its values are
+    /// LuT indices rather than raw values.
+    #[error("map stores LuT indices, not raw values")]
+    HasLut,
+
+    /// The map's LuT is in a separate file, which has to be loaded
+    /// with `RegionMap::load_lut` before values can be resolved.
+    #[error("map's LuT is in a separate .lut file, which hasn't been loaded")]
+    LutNotLoaded,
+
+    /// A LuT was loaded into a map that has its own.
+    #[error("map's LuT is in the map file, not a separate one")]
+    LutNotSplit,
+
+    /// The map file is compressed, so it can't be memory-mapped.
+    #[error("map is compressed and must be opened with RegionMap::open")]
+    Compressed,
+
+    /// zstd is native code, so WebAssembly builds can't read or write
+    /// zstd compressed maps.
+    #[error("zstd compression isn't supported on this target")]
+    ZstdUnsupported,
+
+    #[error("too many values: at most {max} supported, found {found}")]
+    TooManyValues { max: usize, found: usize },
+
+    #[error("invalid cell: {0}")]
+    CellParse(String),
+
+    /// A cell can't be brought to the map's resolution, or the map
+    /// doesn't know its resolution.
+    #[error("resolution mismatch: {0}")]
+    ResolutionMismatch(String),
+
+    #[error("disktree: {0}")]
+    DiskTree(#[from] hextree::Error),
+
+    /// GeoJSON input that couldn't be parsed.
+    #[error("bad GeoJSON: {0}")]
+    GeoJson(#[from] geojson::Error),
+
+    /// A TopoJSON topology that can't be converted to GeoJSON.
+    #[error("bad TopoJSON: {0}")]
+    TopoJson(String),
+
+    /// A GeoJSON feature that can't be tessellated.
+    #[error("feature {idx}: {reason}")]
+    Feature { idx: usize, reason: String },
+
+    #[error("compaction failed: {0}")]
+    Compaction(#[from] CompactionError),
+
+    /// Compaction returned a cell along with one of its ancestors,
+    /// which would store the cell twice. This is a bug in compaction,
+    /// not in the input.
+    #[error("compacted cells {cell} and {ancestor} overlap")]
+    NestedCells {
+        cell: CellIndex,
+        ancestor: CellIndex,
+    },
+
+    /// Generation was cancelled through `WorldOptions::cancel`.
+    #[error("cancelled")]
+    Cancelled,
+
+    /// A worker thread panicked or hung up.
+    #[error("worker thread: {0}")]
+    Thread(String),
+}
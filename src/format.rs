@@ -0,0 +1,828 @@
+use crate::{Error, Result};
+use bincode::Options;
+use byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use h3o::Resolution;
+use hextree::{Cell, HexTreeMap};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+use tracing::info_span;
+
+/// Magic bytes identifying an lwreg map file.
+pub const MAGIC: &[u8; 5] = b"LWREG";
+
+/// Current on-disk format version.
+pub const FORMAT_VERSION: u8 = 7;
+
+/// First version to start with [`MAGIC`] and a header. Earlier files
+/// are a bare disktree and LuT, with the version packed into the top
+/// byte of a trailing u64 LuT offset: 0 for `u8` values, 1 for `u16`.
+const HEADER_VERSION: u8 = 2;
+
+/// First version whose header holds the resolution and cell count.
+pub(crate) const CELL_COUNT_VERSION: u8 = 3;
+
+/// First version whose trailer holds a CRC32 of the LuT.
+const LUT_CRC_VERSION: u8 = 4;
+
+/// First version with the LuT offset table and current trailer.
+pub(crate) const OFFSET_TABLE_VERSION: u8 = 5;
+
+/// First version whose header holds flags.
+const FLAGS_VERSION: u8 = 6;
+
+/// First version whose header holds dependency versions.
+const DEP_VERSIONS_VERSION: u8 = 7;
+
+/// Version of h3o this crate was built with, as recorded in the
+/// headers of maps it writes.
+pub const H3O_VERSION: &str = env!("LWREG_H3O_VERSION");
+
+/// Version of hextree this crate was built with, with its commit when
+/// it's a git dependency. A different hextree could in principle
+/// encode disktrees differently.
+pub const HEXTREE_VERSION: &str = env!("LWREG_HEXTREE_VERSION");
+
+/// Length of the trailer: the LuT's CRC32 followed by the u64
+/// offsets of the LuT and its offset table.
+pub(crate) const TRAILER_LEN: u64 = 20;
+
+/// Capacity of the buffer maps are written through. Serializing a
+/// disktree makes many small writes, so without one writing a large
+/// map to a file is bound by syscalls.
+const WRITE_BUF_LEN: usize = 1 << 20;
+
+/// Resolution byte marking a map built from cells of unknown
+/// resolution.
+const UNKNOWN_RESOLUTION: u8 = 0xFF;
+
+/// Header flag set when the disktree's values are `u16` LuT indices.
+const FLAG_LUT: u8 = 1;
+
+/// Header flag set when the LuT is gzipped.
+const FLAG_GZIP_LUT: u8 = 2;
+
+/// Header flag set when the LuT is in a separate file.
+const FLAG_SPLIT_LUT: u8 = 4;
+
+/// Header flag set when LuT indices are stored as `u8`s.
+const FLAG_VALUE_U8: u8 = 8;
+
+/// Header flag set when LuT indices are stored as `u32`s. With
+/// neither this nor [`FLAG_VALUE_U8`] they're `u16`s, as they always
+/// were before the width could be chosen.
+const FLAG_VALUE_U32: u8 = 16;
+
+/// Metadata stored at the start of a map file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Format version the file was written with.
+    pub version: u8,
+    /// Whether values are indices into the LuT, as written by
+    /// [`write_region_map`], rather than raw values written by
+    /// [`write_map`].
+    pub has_lut: bool,
+    /// Whether the LuT is gzipped. Its offset table then gives
+    /// positions in the decompressed LuT rather than in the file.
+    pub gzip_lut: bool,
+    /// Whether the LuT is in a separate `.lut` file, as written by
+    /// [`write_split_region_map`]. The map file's own LuT is then
+    /// empty.
+    pub split_lut: bool,
+    /// How many bytes each stored LuT index takes. Meaningless for
+    /// maps without a LuT.
+    pub value_width: ValueWidth,
+    /// Resolution the map was built at, if known.
+    pub resolution: Option<Resolution>,
+    /// Number of cells stored in the disktree.
+    pub cell_count: u64,
+    /// [`H3O_VERSION`] of the writer, or "unknown".
+    pub h3o_version: String,
+    /// [`HEXTREE_VERSION`] of the writer, or "unknown".
+    pub hextree_version: String,
+}
+
+impl Header {
+    /// Returns the header this crate writes for a map of `cell_count`
+    /// raw values built at `resolution`.
+    fn new(resolution: Option<Resolution>, cell_count: usize) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            has_lut: false,
+            gzip_lut: false,
+            split_lut: false,
+            value_width: ValueWidth::U16,
+            resolution,
+            cell_count: cell_count as u64,
+            h3o_version: H3O_VERSION.to_owned(),
+            hextree_version: HEXTREE_VERSION.to_owned(),
+        }
+    }
+
+    fn write<W: Write>(&self, wtr: &mut W) -> Result<()> {
+        wtr.write_all(MAGIC)?;
+        wtr.write_u8(self.version)?;
+        let mut flags = 0;
+        if self.has_lut {
+            flags |= FLAG_LUT;
+        }
+        if self.gzip_lut {
+            flags |= FLAG_GZIP_LUT;
+        }
+        if self.split_lut {
+            flags |= FLAG_SPLIT_LUT;
+        }
+        match self.value_width {
+            ValueWidth::U8 => flags |= FLAG_VALUE_U8,
+            ValueWidth::U16 => (),
+            ValueWidth::U32 => flags |= FLAG_VALUE_U32,
+        }
+        wtr.write_u8(flags)?;
+        wtr.write_u8(self.resolution.map_or(UNKNOWN_RESOLUTION, u8::from))?;
+        wtr.write_u64::<LE>(self.cell_count)?;
+        for version in [&self.h3o_version, &self.hextree_version] {
+            // Versions come from Cargo.lock and are short and ASCII.
+            let version = &version.as_bytes()[..version.len().min(usize::from(u8::MAX))];
+            wtr.write_u8(version.len() as u8)?;
+            wtr.write_all(version)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a header of any version from [`HEADER_VERSION`] on.
+    /// Fields older versions lack are filled in as they were then:
+    /// values are `u16` LuT indices, and versions are "unknown". The
+    /// cell count of a file from before [`CELL_COUNT_VERSION`] is left
+    /// at zero for the caller to count.
+    fn read<R: Read>(rdr: &mut R) -> Result<Self> {
+        let mut magic = [0_u8; MAGIC.len()];
+        if rdr.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version = rdr.read_u8()?;
+        if !(HEADER_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let flags = if version >= FLAGS_VERSION {
+            rdr.read_u8()?
+        } else {
+            FLAG_LUT
+        };
+        let known = FLAG_LUT | FLAG_GZIP_LUT | FLAG_SPLIT_LUT | FLAG_VALUE_U8 | FLAG_VALUE_U32;
+        if flags & !known != 0 {
+            return Err(Error::Corrupt(format!("unknown header flags {flags:#04x}")));
+        }
+        let value_width = match (flags & FLAG_VALUE_U8 != 0, flags & FLAG_VALUE_U32 != 0) {
+            (false, false) => ValueWidth::U16,
+            (true, false) => ValueWidth::U8,
+            (false, true) => ValueWidth::U32,
+            (true, true) => return Err(Error::Corrupt("conflicting value widths".to_owned())),
+        };
+        let (resolution, cell_count) = if version >= CELL_COUNT_VERSION {
+            let resolution = match rdr.read_u8()? {
+                UNKNOWN_RESOLUTION => None,
+                res => Some(
+                    Resolution::try_from(res)
+                        .map_err(|_| Error::Corrupt(format!("bad resolution byte {res}")))?,
+                ),
+            };
+            (resolution, rdr.read_u64::<LE>()?)
+        } else {
+            (None, 0)
+        };
+        let mut read_version = || -> Result<String> {
+            if version < DEP_VERSIONS_VERSION {
+                return Ok("unknown".to_owned());
+            }
+            let mut version = vec![0; usize::from(rdr.read_u8()?)];
+            rdr.read_exact(&mut version)?;
+            String::from_utf8(version)
+                .map_err(|_| Error::Corrupt("non-UTF-8 dependency version".to_owned()))
+        };
+        let h3o_version = read_version()?;
+        let hextree_version = read_version()?;
+        Ok(Self {
+            version,
+            has_lut: flags & FLAG_LUT != 0,
+            gzip_lut: flags & FLAG_GZIP_LUT != 0,
+            split_lut: flags & FLAG_SPLIT_LUT != 0,
+            value_width,
+            resolution,
+            cell_count,
+            h3o_version,
+            hextree_version,
+        })
+    }
+}
+
+/// Reads the header, leaving `rdr` at the start of the disktree.
+///
+/// A file from before [`HEADER_VERSION`] has no header, so one is made
+/// up for it if its trailer looks like one of theirs. Its
+/// cell count is left at zero, as for [`Header::read`].
+pub(crate) fn read_header<R: Read + Seek>(rdr: &mut R) -> Result<Header> {
+    match Header::read(rdr) {
+        Err(Error::BadMagic) => (),
+        header => return header,
+    }
+    let len = rdr.seek(SeekFrom::End(0))?;
+    // The LuT is at least its u64 length, followed by the trailer.
+    let version = match read_packed_trailer(rdr) {
+        Ok((lut_pos, version)) if version < HEADER_VERSION && lut_pos + 16 <= len => version,
+        _ => return Err(Error::BadMagic),
+    };
+    rdr.rewind()?;
+    Ok(Header {
+        version,
+        has_lut: true,
+        value_width: if version == 0 {
+            ValueWidth::U8
+        } else {
+            ValueWidth::U16
+        },
+        h3o_version: "unknown".to_owned(),
+        hextree_version: "unknown".to_owned(),
+        ..Header::new(None, 0)
+    })
+}
+
+/// Reads the trailing u64 of a file from before [`HEADER_VERSION`],
+/// returning the LuT offset and version packed into it.
+fn read_packed_trailer<R: Read + Seek>(rdr: &mut R) -> Result<(u64, u8)> {
+    rdr.seek(SeekFrom::End(-8))?;
+    let packed = rdr.read_u64::<LE>()?;
+    Ok((packed & ((1 << 56) - 1), (packed >> 56) as u8))
+}
+
+/// How many bytes a region map stores each LuT index in.
+///
+/// Narrower indices make smaller maps, but cap how many LuT entries
+/// they can index. This crate's LuTs hold at most 65536 entries, so
+/// `U32` only leaves room for future writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValueWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl ValueWidth {
+    /// Returns the narrowest width that indexes a LuT of `len`
+    /// entries.
+    pub fn for_len(len: usize) -> Self {
+        if len <= Self::U8.max_len() {
+            Self::U8
+        } else if len <= Self::U16.max_len() {
+            Self::U16
+        } else {
+            Self::U32
+        }
+    }
+
+    /// Returns the most LuT entries this width can index.
+    pub fn max_len(self) -> usize {
+        match self {
+            Self::U8 => usize::from(u8::MAX) + 1,
+            Self::U16 => usize::from(u16::MAX) + 1,
+            Self::U32 => u32::MAX as usize + 1,
+        }
+    }
+
+    pub(crate) fn read<R: Read + ?Sized>(self, rdr: &mut R) -> io::Result<u32> {
+        match self {
+            Self::U8 => rdr.read_u8().map(u32::from),
+            Self::U16 => rdr.read_u16::<LE>().map(u32::from),
+            Self::U32 => rdr.read_u32::<LE>(),
+        }
+    }
+
+    fn write<W: Write + ?Sized>(self, wtr: &mut W, idx: u16) -> io::Result<()> {
+        match self {
+            // Callers check that every index fits first.
+            Self::U8 => wtr.write_u8(idx as u8),
+            Self::U16 => wtr.write_u16::<LE>(idx),
+            Self::U32 => wtr.write_u32::<LE>(u32::from(idx)),
+        }
+    }
+}
+
+/// How a whole map file is compressed, if at all.
+///
+/// Lookups seek all over a map, which a compressed stream can't
+/// support, so there's no compressed layout as such: a compressed map
+/// is the compressed bytes of an uncompressed one. Gzip and zstd
+/// streams start with magic bytes that can't be mistaken for
+/// [`MAGIC`], so [`RegionMap::open`] recognizes them and decompresses
+/// the map into memory, trading a slower open for smaller files.
+///
+/// [`RegionMap::open`]: crate::RegionMap::open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    const GZIP_MAGIC: &'static [u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &'static [u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+    /// Returns the compression of a file starting with `prefix`, which
+    /// needs at most four bytes.
+    pub fn detect(prefix: &[u8]) -> Self {
+        if prefix.starts_with(Self::GZIP_MAGIC) {
+            Self::Gzip
+        } else if prefix.starts_with(Self::ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Writes the map file in `map` to `wtr`, compressed.
+    pub fn compress<W: Write>(self, mut wtr: W, map: &[u8]) -> Result<()> {
+        match self {
+            Self::None => wtr.write_all(map)?,
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(wtr, flate2::Compression::default());
+                encoder.write_all(map)?;
+                encoder.finish()?;
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Zstd => zstd::stream::copy_encode(map, wtr, 0)?,
+            #[cfg(target_arch = "wasm32")]
+            Self::Zstd => return Err(Error::ZstdUnsupported),
+        }
+        Ok(())
+    }
+
+    /// Reads all of `rdr` and decompresses it.
+    pub fn decompress<R: Read>(self, mut rdr: R) -> Result<Vec<u8>> {
+        let mut map = Vec::new();
+        match self {
+            Self::None => rdr.read_to_end(&mut map)?,
+            Self::Gzip => GzDecoder::new(rdr).read_to_end(&mut map)?,
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Zstd => zstd::stream::Decoder::new(rdr)?.read_to_end(&mut map)?,
+            #[cfg(target_arch = "wasm32")]
+            Self::Zstd => return Err(Error::ZstdUnsupported),
+        };
+        Ok(map)
+    }
+}
+
+/// A view into `inner` that starts at byte `start`.
+///
+/// The disktree is not at the start of the file, so this makes it
+/// believe it is: absolute seeks and reported positions are relative
+/// to `start`.
+pub(crate) struct Section<T> {
+    inner: T,
+    start: u64,
+}
+
+impl<T> Section<T> {
+    pub(crate) fn new(inner: T, start: u64) -> Self {
+        Self { inner, start }
+    }
+}
+
+impl<T: Read> Read for Section<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Section<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Section<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => SeekFrom::Start(self.start + n),
+            other => other,
+        };
+        self.inner
+            .seek(pos)?
+            .checked_sub(self.start)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before section"))
+    }
+}
+
+/// The end of a map file, locating the LuT.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Trailer {
+    /// CRC32 of the LuT and its offset table.
+    pub(crate) lut_crc: u32,
+    pub(crate) lut_pos: u64,
+    /// Position of the offset table, one u64 per LuT entry giving
+    /// the entry's position in the file.
+    pub(crate) offsets_pos: u64,
+    /// Position of the trailer itself, where the offset table ends.
+    pub(crate) pos: u64,
+}
+
+impl Trailer {
+    /// Number of LuT entries, as given by the offset table's length.
+    pub(crate) fn lut_len(&self) -> usize {
+        ((self.pos - self.offsets_pos) / 8) as usize
+    }
+}
+
+/// Reads the trailer, checking that the offsets it holds are in
+/// order and fall before it.
+pub(crate) fn read_trailer<R: Read + Seek>(rdr: &mut R) -> Result<Trailer> {
+    let len = rdr.seek(SeekFrom::End(0))?;
+    if len < TRAILER_LEN {
+        return Err(Error::Corrupt(format!(
+            "file too short for trailer ({len} bytes)"
+        )));
+    }
+    let pos = rdr.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    let lut_crc = rdr.read_u32::<LE>()?;
+    let lut_pos = rdr.read_u64::<LE>()?;
+    let offsets_pos = rdr.read_u64::<LE>()?;
+    // Files from other tools, or truncated ones, most often fail
+    // here, so say so plainly rather than as the generic check below.
+    if lut_pos > len {
+        return Err(Error::Corrupt(format!(
+            "lut offset {lut_pos} exceeds file size {len}"
+        )));
+    }
+    if lut_pos > offsets_pos || offsets_pos > pos || !(pos - offsets_pos).is_multiple_of(8) {
+        return Err(Error::Corrupt(format!(
+            "bad LuT offsets {lut_pos} and {offsets_pos} for trailer at {pos}"
+        )));
+    }
+    Ok(Trailer {
+        lut_crc,
+        lut_pos,
+        offsets_pos,
+        pos,
+    })
+}
+
+/// Reads the serialized LuT, checking it and its offset table against
+/// the trailer's CRC, and decompresses it if `gzipped`.
+pub(crate) fn read_lut_bytes<R: Read + Seek>(
+    rdr: &mut R,
+    trailer: &Trailer,
+    gzipped: bool,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![0; (trailer.pos - trailer.lut_pos) as usize];
+    rdr.seek(SeekFrom::Start(trailer.lut_pos))?;
+    rdr.read_exact(&mut buf)?;
+    let crc = crc32fast::hash(&buf);
+    if crc != trailer.lut_crc {
+        return Err(Error::LutChecksum {
+            expected: trailer.lut_crc,
+            found: crc,
+        });
+    }
+    buf.truncate((trailer.offsets_pos - trailer.lut_pos) as usize);
+    if !gzipped {
+        return Ok(buf);
+    }
+    let mut lut = Vec::new();
+    GzDecoder::new(buf.as_slice())
+        .read_to_end(&mut lut)
+        .map_err(|e| Error::Corrupt(format!("bad gzipped LuT: {e}")))?;
+    Ok(lut)
+}
+
+/// Deserializes a LuT read by [`read_lut_bytes`].
+pub(crate) fn decode_lut<T: DeserializeOwned>(bytes: &[u8]) -> Result<Vec<T>> {
+    bincode::deserialize(bytes).map_err(Error::LutDeserialize)
+}
+
+/// Reads the whole LuT, as for [`read_lut_bytes`].
+pub(crate) fn read_lut<R: Read + Seek, T: DeserializeOwned>(
+    rdr: &mut R,
+    trailer: &Trailer,
+    gzipped: bool,
+) -> Result<Vec<T>> {
+    decode_lut(&read_lut_bytes(rdr, trailer, gzipped)?)
+}
+
+/// Reads the LuT's offset table, checking that the offsets are in
+/// order and within `lut`, the span of the LuT they point into.
+pub(crate) fn read_lut_offsets<R: Read + Seek>(
+    rdr: &mut R,
+    trailer: &Trailer,
+    lut: Range<u64>,
+) -> Result<Vec<u64>> {
+    rdr.seek(SeekFrom::Start(trailer.offsets_pos))?;
+    let mut offsets = Vec::with_capacity(trailer.lut_len());
+    let mut prev = lut.start;
+    for idx in 0..trailer.lut_len() {
+        let pos = rdr.read_u64::<LE>()?;
+        if pos < prev || pos >= lut.end {
+            return Err(Error::Corrupt(format!(
+                "bad offset {pos} for LuT entry {idx}"
+            )));
+        }
+        offsets.push(pos);
+        prev = pos;
+    }
+    Ok(offsets)
+}
+
+/// Reads the single LuT entry between `pos` and `end`.
+pub(crate) fn read_lut_entry<R: Read + Seek, T: DeserializeOwned>(
+    rdr: &mut R,
+    pos: u64,
+    end: u64,
+) -> Result<T> {
+    rdr.seek(SeekFrom::Start(pos))?;
+    // Same encoding as `bincode::serialize`, but bounded so that a
+    // corrupt length can't make us allocate past the entry.
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(end - pos)
+        .deserialize_from(rdr)
+        .map_err(Error::LutDeserialize)
+}
+
+/// Reads the LuT of a file from before [`OFFSET_TABLE_VERSION`],
+/// checking it against its CRC if the file has one.
+///
+/// These files have no offset table, so the returned trailer has an
+/// empty one ending where the LuT does. Find the entries in the
+/// returned LuT with [`string_lut_offsets`].
+pub(crate) fn read_old_lut<R: Read + Seek>(rdr: &mut R, version: u8) -> Result<(Trailer, Vec<u8>)> {
+    let trailer_len: u64 = if version >= LUT_CRC_VERSION { 12 } else { 8 };
+    let len = rdr.seek(SeekFrom::End(0))?;
+    if len < trailer_len {
+        return Err(Error::Corrupt(format!(
+            "file too short for trailer ({len} bytes)"
+        )));
+    }
+    let pos = rdr.seek(SeekFrom::End(-(trailer_len as i64)))?;
+    let lut_crc = if version >= LUT_CRC_VERSION {
+        Some(rdr.read_u32::<LE>()?)
+    } else {
+        None
+    };
+    let lut_pos = if version < HEADER_VERSION {
+        read_packed_trailer(rdr)?.0
+    } else {
+        rdr.read_u64::<LE>()?
+    };
+    if lut_pos > pos {
+        return Err(Error::Corrupt(format!(
+            "lut offset {lut_pos} exceeds file size {len}"
+        )));
+    }
+    let mut buf = vec![0; (pos - lut_pos) as usize];
+    rdr.seek(SeekFrom::Start(lut_pos))?;
+    rdr.read_exact(&mut buf)?;
+    let crc = crc32fast::hash(&buf);
+    if let Some(expected) = lut_crc {
+        if expected != crc {
+            return Err(Error::LutChecksum {
+                expected,
+                found: crc,
+            });
+        }
+    }
+    let trailer = Trailer {
+        lut_crc: crc,
+        lut_pos,
+        offsets_pos: pos,
+        pos,
+    };
+    Ok((trailer, buf))
+}
+
+/// Returns the position in `lut`, a serialized `Vec<String>`, of each
+/// of its entries.
+///
+/// Files from before [`OFFSET_TABLE_VERSION`] only ever held strings,
+/// so their entries can be found without knowing their type.
+pub(crate) fn string_lut_offsets(lut: &[u8]) -> Result<Vec<u64>> {
+    let truncated = || Error::Corrupt("LuT is truncated".to_owned());
+    let mut rdr = io::Cursor::new(lut);
+    let count = rdr.read_u64::<LE>().map_err(|_| truncated())?;
+    let mut offsets = Vec::new();
+    for _ in 0..count {
+        let pos = rdr.position();
+        let str_len = rdr.read_u64::<LE>().map_err(|_| truncated())?;
+        let end = (pos + 8)
+            .checked_add(str_len)
+            .filter(|&end| end <= lut.len() as u64)
+            .ok_or_else(truncated)?;
+        offsets.push(pos);
+        rdr.set_position(end);
+    }
+    Ok(offsets)
+}
+
+/// Writes the header and `map` as a disktree to `wtr`, followed by
+/// `lut` and the trailer.
+///
+/// `resolution` is the resolution the map was built at, or `None` if
+/// it isn't known. With `gzip_lut` the LuT is gzipped, which can shrink
+/// a LuT of JSON values severalfold, but readers then have to load
+/// all of it on open rather than reading entries as needed.
+///
+/// LuT indices are stored `value_width` wide, or if that's `None` in
+/// the narrowest [`ValueWidth`] that indexes `lut`. A wider one leaves
+/// room to add entries later without growing every stored value. It's
+/// an error for `lut` to have more entries than `value_width` indexes.
+pub fn write_region_map<W, S>(
+    wtr: W,
+    resolution: Option<Resolution>,
+    map: &HexTreeMap<u16>,
+    lut: &[S],
+    gzip_lut: bool,
+    value_width: Option<ValueWidth>,
+) -> Result<()>
+where
+    W: Write + Seek,
+    S: Serialize,
+{
+    let value_width = check_lut_indices(map, lut.len(), value_width)?;
+    let header = Header {
+        has_lut: true,
+        gzip_lut,
+        value_width,
+        ..Header::new(resolution, map.len())
+    };
+    write_file(wtr, header, map, lut, |wtr, &val| {
+        value_width.write(wtr, val)
+    })
+}
+
+/// Writes a map as for [`write_region_map`], but with the LuT in
+/// `lut_wtr` rather than after the disktree in `tree_wtr`.
+///
+/// The disktree then doesn't change when only the LuT's values do, so
+/// the two can be cached separately. [`RegionMap::open`] reads the LuT
+/// from a `.lut` file next to the map, i.e. the map's path with its
+/// extension replaced; other readers load it with
+/// [`RegionMap::load_lut`].
+///
+/// [`RegionMap::open`]: crate::RegionMap::open
+/// [`RegionMap::load_lut`]: crate::RegionMap::load_lut
+pub fn write_split_region_map<W, L, S>(
+    tree_wtr: W,
+    lut_wtr: L,
+    resolution: Option<Resolution>,
+    map: &HexTreeMap<u16>,
+    lut: &[S],
+    gzip_lut: bool,
+    value_width: Option<ValueWidth>,
+) -> Result<()>
+where
+    W: Write + Seek,
+    L: Write + Seek,
+    S: Serialize,
+{
+    let value_width = check_lut_indices(map, lut.len(), value_width)?;
+    let header = Header {
+        has_lut: true,
+        gzip_lut,
+        split_lut: true,
+        value_width,
+        ..Header::new(resolution, map.len())
+    };
+    write_file::<_, _, _, S>(tree_wtr, header, map, &[], |wtr, &val| {
+        value_width.write(wtr, val)
+    })?;
+    let mut lut_wtr = BufWriter::new(lut_wtr);
+    write_lut(&mut lut_wtr, lut, gzip_lut)?;
+    lut_wtr.into_inner().map_err(|e| e.into_error())?;
+    Ok(())
+}
+
+/// Readers trust that every value indexes the LuT, so make sure
+/// before writing anything, returning the width to store them in.
+fn check_lut_indices(
+    map: &HexTreeMap<u16>,
+    len: usize,
+    value_width: Option<ValueWidth>,
+) -> Result<ValueWidth> {
+    let value_width = value_width.unwrap_or_else(|| ValueWidth::for_len(len));
+    // Indices are u16 however wide they're stored, so entries past
+    // the u16 range could never be looked up.
+    let max = value_width.max_len().min(ValueWidth::U16.max_len());
+    if len > max {
+        return Err(Error::TooManyValues { max, found: len });
+    }
+    match map.iter().find(|(_, &idx)| usize::from(idx) >= len) {
+        Some((_, &idx)) => Err(Error::LutIndex {
+            idx: u32::from(idx),
+            len,
+        }),
+        None => Ok(value_width),
+    }
+}
+
+/// Writes the header and `map` as a disktree to `wtr`, storing each
+/// value as `write` encodes it rather than via a LuT.
+///
+/// Read values back with [`RegionMap::read_value`]. See
+/// [`write_region_map`] for `resolution`.
+///
+/// [`RegionMap::read_value`]: crate::RegionMap::read_value
+pub fn write_map<W, V, F>(
+    wtr: W,
+    resolution: Option<Resolution>,
+    map: &HexTreeMap<V>,
+    write: F,
+) -> Result<()>
+where
+    W: Write + Seek,
+    F: Fn(&mut dyn Write, &V) -> io::Result<()>,
+{
+    write_file::<_, _, _, String>(wtr, Header::new(resolution, map.len()), map, &[], write)
+}
+
+/// Writes `map` as for [`write_map`], storing each value cell as its
+/// u64 index rather than a LuT index. This suits values that are
+/// cells themselves, e.g. mappings from one tiling to another.
+///
+/// Read values back with [`RegionMap::lookup_cell`].
+///
+/// [`RegionMap::lookup_cell`]: crate::RegionMap::lookup_cell
+pub fn write_cell_map<W: Write + Seek>(
+    wtr: W,
+    resolution: Option<Resolution>,
+    map: &HexTreeMap<Cell>,
+) -> Result<()> {
+    write_map(wtr, resolution, map, |wtr, cell| {
+        wtr.write_u64::<LE>(cell.into_raw())
+    })
+}
+
+fn write_file<W, V, F, S>(
+    wtr: W,
+    header: Header,
+    map: &HexTreeMap<V>,
+    lut: &[S],
+    write: F,
+) -> Result<()>
+where
+    W: Write + Seek,
+    F: Fn(&mut dyn Write, &V) -> io::Result<()>,
+    S: Serialize,
+{
+    // Seeking a `BufWriter` flushes it first, so positions, including
+    // `SeekFrom::End` below, are the same as for the bare writer.
+    let mut wtr = BufWriter::with_capacity(WRITE_BUF_LEN, wtr);
+    header.write(&mut wtr)?;
+    let disktree_pos = wtr.stream_position()?;
+    info_span!("write_disktree")
+        .in_scope(|| map.to_disktree(Section::new(&mut wtr, disktree_pos), write))?;
+    // Maps without a LuT, or with a split one, get an empty one so
+    // that every file has the same layout.
+    write_lut(&mut wtr, lut, header.gzip_lut)?;
+    // Dropping a `BufWriter` would swallow any error from its final
+    // flush.
+    wtr.into_inner().map_err(|e| e.into_error())?;
+    Ok(())
+}
+
+/// Appends `lut` to the end of `wtr`, followed by the position of
+/// each entry so that readers can fetch one without the rest, and the
+/// trailer.
+///
+/// A serialized `Vec` is its length followed by its serialized
+/// entries, so the first entry starts 8 bytes in. A gzipped LuT can't
+/// be read piecemeal, so its offsets are into the decompressed LuT
+/// instead.
+fn write_lut<W: Write + Seek, S: Serialize>(wtr: &mut W, lut: &[S], gzip_lut: bool) -> Result<()> {
+    let _span = info_span!("write_lut", len = lut.len()).entered();
+    let lut_pos = wtr.seek(SeekFrom::End(0))?;
+    let mut buf = bincode::serialize(lut).map_err(Error::LutSerialize)?;
+    if gzip_lut {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&buf)?;
+        buf = encoder.finish()?;
+    }
+    let offsets_pos = lut_pos + buf.len() as u64;
+    let mut entry_pos = if gzip_lut { 8 } else { lut_pos + 8 };
+    for entry in lut {
+        buf.write_u64::<LE>(entry_pos)?;
+        entry_pos += bincode::serialized_size(entry).map_err(Error::LutSerialize)?;
+    }
+    wtr.write_all(&buf)?;
+
+    // Write the checksum and positions at the end of the file.
+    wtr.write_u32::<LE>(crc32fast::hash(&buf))?;
+    wtr.write_u64::<LE>(lut_pos)?;
+    wtr.write_u64::<LE>(offsets_pos)?;
+    Ok(())
+}
@@ -0,0 +1,859 @@
+use crate::{Error, Result};
+use geojson::{Feature, JsonObject};
+use h3o::{
+    geom::{ContainmentMode, Geometry, PolyfillConfig, ToCells},
+    CellIndex, LatLng, Resolution,
+};
+use hextree::{Cell, HexTreeMap};
+use log::{debug, info};
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::atomic::AtomicBool,
+};
+use tracing::info_span;
+
+/// What to do when a cell is claimed by more than one region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the last claim
+    Last,
+    /// Keep the first claim
+    First,
+    /// Abort
+    Error,
+    /// Keep the last claim and report how many cells conflicted
+    Warn,
+}
+
+impl ConflictPolicy {
+    /// Inserts `val` at `cell`, returning every existing claim with a
+    /// different value that overlaps it, as the cell the two share and
+    /// the existing value.
+    ///
+    /// An existing claim can be on `cell` itself or one of its
+    /// parents, sharing all of `cell`, or on finer cells within it,
+    /// e.g. an earlier set's cells that a later compacted cell covers.
+    ///
+    /// With [`ConflictPolicy::First`] existing claims are kept and
+    /// `val` only fills the parts of `cell` nobody has claimed,
+    /// otherwise `val` replaces them. It's up to the caller to report
+    /// or abort on a conflict.
+    pub fn insert<V: Copy + PartialEq>(
+        self,
+        map: &mut HexTreeMap<V>,
+        cell: Cell,
+        val: V,
+    ) -> Vec<(Cell, V)> {
+        if let Some((_, &existing)) = map.get(cell) {
+            // A stored cell has nothing stored under it, so this is
+            // the only claim.
+            if existing == val {
+                return Vec::new();
+            }
+            if self != ConflictPolicy::First {
+                map.insert(cell, val);
+            }
+            return vec![(cell, existing)];
+        }
+        let conflicts: Vec<(Cell, V)> = map
+            .subtree_iter(cell)
+            .filter(|&(_, &existing)| existing != val)
+            .map(|(claimed, &existing)| (claimed, existing))
+            .collect();
+        if self == ConflictPolicy::First && !conflicts.is_empty() {
+            fill_unclaimed(map, cell, val);
+        } else {
+            // Replaces everything stored under `cell`.
+            map.insert(cell, val);
+        }
+        conflicts
+    }
+}
+
+/// Inserts `val` at each of the largest cells within `cell` that
+/// nothing in `map` claims, leaving existing claims as they are.
+/// Neither `cell` nor its parents may be stored.
+fn fill_unclaimed<V: Copy>(map: &mut HexTreeMap<V>, cell: Cell, val: V) {
+    let cell = CellIndex::try_from(cell.into_raw()).expect("stored cells are valid");
+    // Something is stored under `cell`, so it can't be at res 15.
+    let res = cell.resolution().succ().expect("cell has children");
+    for child in cell.children(res) {
+        let child = Cell::from_raw(child.into()).expect("children are valid");
+        if map.subtree_iter(child).next().is_none() {
+            map.insert(child, val);
+        } else if map.get(child).is_none() {
+            fill_unclaimed(map, child, val);
+        }
+    }
+}
+
+/// Tessellates a GeoJSON feature into cells at `resolution`, using
+/// `containment` to decide which cells along its boundary are kept.
+///
+/// A GeometryCollection is tessellated member by member, so cells in
+/// more than one member are returned more than once; see
+/// [`dedup_cells`].
+pub fn to_cells(
+    idx: usize,
+    feature: Feature,
+    resolution: Resolution,
+    containment: ContainmentMode,
+) -> Result<(usize, JsonObject, Vec<CellIndex>)> {
+    let _span = info_span!("tessellate", idx).entered();
+    info!("generating {:?}", feature.properties);
+    let start = std::time::Instant::now();
+    let feature_err = |reason: String| Error::Feature { idx, reason };
+    let properties = feature
+        .properties
+        .ok_or_else(|| feature_err("no properties".to_string()))?;
+    let geometry = feature
+        .geometry
+        .ok_or_else(|| feature_err("missing geometry".to_string()))?;
+    let config = PolyfillConfig::new(resolution).containment_mode(containment);
+    let mut cells = Vec::new();
+    tessellate(&geometry, config, &mut cells).map_err(feature_err)?;
+    debug!("generated {:?} in {:?}", properties, start.elapsed());
+    Ok((idx, properties, cells))
+}
+
+/// Appends the cells of `geometry` to `cells`, recursing into the
+/// members of a GeometryCollection. Errors are reasons for an
+/// [`Error::Feature`], locating the failing member if any.
+fn tessellate(
+    geometry: &geojson::Geometry,
+    config: PolyfillConfig,
+    cells: &mut Vec<CellIndex>,
+) -> std::result::Result<(), String> {
+    if let geojson::Value::GeometryCollection(members) = &geometry.value {
+        for (member_idx, member) in members.iter().enumerate() {
+            tessellate(member, config, cells)
+                .map_err(|reason| format!("member {member_idx}: {reason}"))?;
+        }
+        return Ok(());
+    }
+    let geometry = Geometry::try_from(geometry).map_err(|e| match geometry_problem(geometry) {
+        Some(problem) => format!("{e} ({problem})"),
+        None => e.to_string(),
+    })?;
+    cells.extend(geometry.to_cells(config));
+    Ok(())
+}
+
+pub fn dedup_cells(mut cells: Vec<CellIndex>) -> Result<Vec<CellIndex>> {
+    cells.sort_unstable();
+    cells.dedup();
+    Ok(cells)
+}
+
+/// Compacts `cells`, which must be free of duplicates, see
+/// [`dedup_cells`].
+///
+/// The result is checked to hold no cell along with one of its
+/// ancestors, failing with [`Error::NestedCells`] if it does.
+pub fn compact_cells(cells: Vec<CellIndex>) -> Result<Vec<CellIndex>> {
+    let compacted: Vec<CellIndex> = CellIndex::compact(cells)?.collect();
+    check_not_nested(&compacted)?;
+    Ok(compacted)
+}
+
+/// Fails if any of `cells` has one of its ancestors among them.
+fn check_not_nested(cells: &[CellIndex]) -> Result<()> {
+    let set: HashSet<CellIndex> = cells.iter().copied().collect();
+    for &cell in cells {
+        let ancestor = std::iter::successors(cell.resolution().pred(), |res| res.pred())
+            .filter_map(|res| cell.parent(res))
+            .find(|parent| set.contains(parent));
+        if let Some(ancestor) = ancestor {
+            return Err(Error::NestedCells { cell, ancestor });
+        }
+    }
+    Ok(())
+}
+
+/// Truncates `cells` to their parents at `resolution`, then dedups
+/// and, if `compact` is set, compacts them.
+///
+/// Cells already coarser than `resolution` can't be truncated and are
+/// kept as-is.
+pub fn coarsen_cells(
+    cells: Vec<CellIndex>,
+    resolution: Resolution,
+    compact: bool,
+) -> Result<Vec<CellIndex>> {
+    let (mut coarser, finer): (Vec<CellIndex>, Vec<CellIndex>) = cells
+        .into_iter()
+        .partition(|cell| cell.resolution() < resolution);
+    let truncated = finer
+        .into_iter()
+        .map(|cell| {
+            cell.parent(resolution)
+                .expect("cell is finer than resolution")
+        })
+        .collect();
+    let truncated = dedup_cells(truncated)?;
+    if compact {
+        coarser.extend(compact_cells(truncated)?);
+    } else {
+        coarser.extend(truncated);
+    }
+    Ok(coarser)
+}
+
+/// Splits every cell in `map` coarser than `min_res` into its
+/// children at `min_res`, each with the cell's value.
+///
+/// Compaction merges cells as far up as their children allow, so this
+/// bounds how coarse a compacted map's cells get. `min_res` shouldn't
+/// be finer than the map's own resolution, or lookups coerced to that
+/// resolution would miss the split cells.
+pub fn cap_compaction<V: Copy>(map: &HexTreeMap<V>, min_res: Resolution) -> Result<HexTreeMap<V>> {
+    let _span = info_span!("cap_compaction").entered();
+    let mut capped = HexTreeMap::new();
+    for (cell, &val) in map.iter() {
+        if cell.res() >= u8::from(min_res) {
+            capped.insert(cell, val);
+            continue;
+        }
+        let cell =
+            CellIndex::try_from(cell.into_raw()).map_err(|e| Error::CellParse(e.to_string()))?;
+        for child in cell.children(min_res) {
+            capped.insert(Cell::from_raw(child.into())?, val);
+        }
+    }
+    Ok(capped)
+}
+
+/// Returns whether a feature of `features` with the region `value`,
+/// as [`gen_world`] would store it with `property`, contains
+/// `latlng` exactly rather than to the resolution of a cell.
+///
+/// This checks a lookup near a border, where a cell can straddle
+/// regions, against the source geometries. Only polygonal geometries
+/// contain anything, and a point right on an edge could go either
+/// way. Features without the property are skipped.
+pub fn region_contains<I>(
+    features: I,
+    property: Option<&str>,
+    value: &str,
+    latlng: LatLng,
+) -> Result<bool>
+where
+    I: IntoIterator<Item = geojson::Result<Feature>>,
+{
+    for (idx, feature) in features.into_iter().enumerate() {
+        let feature = feature?;
+        let (Some(properties), Some(geometry)) = (feature.properties, feature.geometry) else {
+            continue;
+        };
+        if !geometry_contains(&geometry.value, latlng.lng(), latlng.lat()) {
+            continue;
+        }
+        match feature_value(idx, properties, property) {
+            Ok(feature_value) if feature_value == value => return Ok(true),
+            _ => (),
+        }
+    }
+    Ok(false)
+}
+
+fn geometry_contains(geometry: &geojson::Value, x: f64, y: f64) -> bool {
+    match geometry {
+        geojson::Value::Polygon(rings) => polygon_contains(rings, x, y),
+        geojson::Value::MultiPolygon(polygons) => {
+            polygons.iter().any(|rings| polygon_contains(rings, x, y))
+        }
+        geojson::Value::GeometryCollection(members) => members
+            .iter()
+            .any(|member| geometry_contains(&member.value, x, y)),
+        _ => false,
+    }
+}
+
+/// Counts the ring edges a ray east from `(x, y)` crosses, so that a
+/// point in a hole is crossed by both rings and comes out outside.
+fn polygon_contains(rings: &[Vec<Vec<f64>>], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    for ring in rings {
+        for edge in ring.windows(2) {
+            let ([x0, y0, ..], [x1, y1, ..]) = (edge[0].as_slice(), edge[1].as_slice()) else {
+                continue;
+            };
+            if (*y0 > y) != (*y1 > y) && x < x0 + (y - y0) * (x1 - x0) / (y1 - y0) {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// Returns the value to store for feature `idx`: its `property`
+/// stringified, or all of `properties` as JSON.
+fn feature_value(idx: usize, mut properties: JsonObject, property: Option<&str>) -> Result<String> {
+    let Some(key) = property else {
+        return Ok(to_sorted_json(&Value::Object(properties)));
+    };
+    match properties.remove(key) {
+        // Strings are stored bare rather than as JSON string literals.
+        Some(Value::String(val)) => Ok(val),
+        Some(val) => Ok(to_sorted_json(&val)),
+        None => Err(Error::Feature {
+            idx,
+            reason: format!("missing property {key}"),
+        }),
+    }
+}
+
+/// Describes `feature` for error messages by its `id_property`, or
+/// its first property, and its geometry type, e.g.
+/// `name=France, MultiPolygon`.
+fn feature_label(feature: &Feature, id_property: Option<&str>) -> String {
+    let id = feature
+        .properties
+        .as_ref()
+        .and_then(|properties| match id_property {
+            Some(key) => properties.get_key_value(key),
+            None => properties.iter().next(),
+        });
+    let geometry = match &feature.geometry {
+        Some(geometry) => geometry_type(&geometry.value),
+        None => "no geometry",
+    };
+    match id {
+        Some((key, Value::String(val))) => format!("{key}={val}, {geometry}"),
+        Some((key, val)) => format!("{key}={val}, {geometry}"),
+        None => geometry.to_owned(),
+    }
+}
+
+fn geometry_type(geometry: &geojson::Value) -> &'static str {
+    match geometry {
+        geojson::Value::Point(_) => "Point",
+        geojson::Value::MultiPoint(_) => "MultiPoint",
+        geojson::Value::LineString(_) => "LineString",
+        geojson::Value::MultiLineString(_) => "MultiLineString",
+        geojson::Value::Polygon(_) => "Polygon",
+        geojson::Value::MultiPolygon(_) => "MultiPolygon",
+        geojson::Value::GeometryCollection(_) => "GeometryCollection",
+    }
+}
+
+/// Finds the first ring of a polygonal `geometry` that h3o would
+/// reject for a reason its errors don't locate: too few positions to
+/// close, or a coordinate that isn't finite.
+fn geometry_problem(geometry: &geojson::Geometry) -> Option<String> {
+    let polygons = match &geometry.value {
+        geojson::Value::Polygon(rings) => std::slice::from_ref(rings),
+        geojson::Value::MultiPolygon(polygons) => polygons.as_slice(),
+        _ => return None,
+    };
+    for (polygon_idx, rings) in polygons.iter().enumerate() {
+        for (ring_idx, ring) in rings.iter().enumerate() {
+            let at = if polygons.len() > 1 {
+                format!("polygon {polygon_idx} ring {ring_idx}")
+            } else {
+                format!("ring {ring_idx}")
+            };
+            if ring.len() < 4 {
+                return Some(format!("{at} has only {} positions", ring.len()));
+            }
+            if let Some(pos) = ring
+                .iter()
+                .position(|position| position.iter().any(|coord| !coord.is_finite()))
+            {
+                return Some(format!("{at} position {pos} is {:?}", ring[pos]));
+            }
+        }
+    }
+    None
+}
+
+/// Serializes `val` with the keys of every object sorted, so equal
+/// values always produce the same bytes whatever order their keys
+/// were parsed in.
+fn to_sorted_json(val: &Value) -> String {
+    struct Sorted<'a>(&'a Value);
+
+    impl Serialize for Sorted<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            match self.0 {
+                Value::Object(obj) => serializer.collect_map(
+                    obj.iter()
+                        .map(|(key, val)| (key, Sorted(val)))
+                        .collect::<BTreeMap<_, _>>(),
+                ),
+                Value::Array(vals) => serializer.collect_seq(vals.iter().map(Sorted)),
+                other => other.serialize(serializer),
+            }
+        }
+    }
+
+    serde_json::to_string(&Sorted(val)).expect("JSON values always serialize")
+}
+
+/// The output of [`gen_world`] and [`gen_world_indices`].
+#[derive(Debug)]
+pub struct World<V = u16> {
+    /// Each cell's value: an index into `lut`, or for
+    /// [`gen_world_indices`] the index of its feature.
+    pub map: HexTreeMap<V>,
+    /// Distinct feature values, or nothing for [`gen_world_indices`].
+    pub lut: Vec<String>,
+    /// Features that tessellated to no cells, e.g. islands smaller
+    /// than a cell, as `(index in features, value)`. They're left out
+    /// of `map` and `lut`.
+    pub empty: Vec<(usize, String)>,
+    /// Errors for features that couldn't be tessellated, in feature
+    /// order. Only populated with `keep_going`; each is an
+    /// [`Error::Feature`] naming its feature.
+    pub failed: Vec<Error>,
+    /// How many cells at the map's resolution each pair of values
+    /// both claimed, keyed by `(earlier, later)` value. Only claims
+    /// with different values overlap.
+    pub overlaps: BTreeMap<(V, V), u64>,
+    /// How many features were read, including empty and failed ones.
+    pub feature_count: usize,
+}
+
+/// How [`gen_world`] and [`gen_world_indices`] tessellate features.
+#[derive(Debug, Clone, Copy)]
+pub struct WorldOptions<'a> {
+    /// Resolution to tessellate at.
+    pub resolution: Resolution,
+    /// Which cells along a feature's boundary are kept, see
+    /// [`to_cells`].
+    pub containment: ContainmentMode,
+    /// Property to take each feature's value from, stringified. It's
+    /// an error for a feature to lack it. Without it the value is the
+    /// whole properties object serialized as JSON. Ignored by
+    /// [`gen_world_indices`].
+    pub property: Option<&'a str>,
+    /// Property that identifies a feature in error messages, along
+    /// with its geometry type. Without it the first property by name
+    /// is used.
+    pub id_property: Option<&'a str>,
+    /// Compact each feature's cells. Otherwise every cell is stored at
+    /// `resolution`.
+    pub compact: bool,
+    /// Record a feature that fails in [`World::failed`] and leave it
+    /// out rather than aborting the whole run.
+    pub keep_going: bool,
+    /// What to do when a feature claims cells an earlier feature with
+    /// a different value claimed. Overlaps are recorded in
+    /// [`World::overlaps`] whatever the policy.
+    pub conflict: ConflictPolicy,
+    /// Checked before each feature is tessellated; once it's set the
+    /// run stops with [`Error::Cancelled`], e.g. from a cancel button.
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+impl WorldOptions<'_> {
+    /// Returns options to tessellate at `resolution` by cell centers,
+    /// storing whole properties, compacting, failing on any bad
+    /// feature, and letting later features win overlaps.
+    pub fn new(resolution: Resolution) -> Self {
+        Self {
+            resolution,
+            containment: ContainmentMode::ContainsCentroid,
+            property: None,
+            id_property: None,
+            compact: true,
+            keep_going: false,
+            conflict: ConflictPolicy::Last,
+            cancel: None,
+        }
+    }
+}
+
+/// A step of a [`gen_world`] or [`gen_world_indices`] run, as passed
+/// to its progress callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// How many features have been through `phase`.
+    pub done: usize,
+    /// How many features there are, if `features` knows its length
+    /// up front, as a collection does but a streaming reader doesn't.
+    pub total: Option<usize>,
+}
+
+/// What a feature has just been through, see [`Progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Tessellated, and compacted if asked. Features are tessellated
+    /// in parallel, so this is reported out of order from any thread.
+    Tessellate,
+    /// Inserted into the map, or recorded as empty or failed. This is
+    /// reported in feature order, and once per feature.
+    Insert,
+}
+
+/// A callback for [`gen_world`] and [`gen_world_indices`] to report
+/// each [`Progress`] to.
+pub type ProgressFn<'a> = &'a (dyn Fn(Progress) + Sync);
+
+/// Tessellates `features` in parallel as `opts` says, returning the
+/// cell map and the property LuT.
+///
+/// Features are pulled from `features` only as threads are free to
+/// tessellate them, so a streaming source such as
+/// [`geojson::FeatureReader`] never has the whole input in memory.
+/// A feature that fails to parse ends the run, even with
+/// [`WorldOptions::keep_going`], as the input can't be trusted past
+/// it. Pass a collection's features as `features.into_iter().map(Ok)`.
+///
+/// `progress`, if given, is called as each feature is tessellated and
+/// as it's inserted. Set [`WorldOptions::cancel`] to stop early.
+///
+/// Features with identical values share a LuT entry, so the LuT
+/// holds at most 65536 distinct values however many features there
+/// are. Entries are in order of first appearance in `features`, and
+/// features are inserted into the map in that order, so overlapping
+/// cells resolve per [`WorldOptions::conflict`] the same way
+/// regardless of the number of threads.
+///
+/// Peak memory is the map and the distinct values, plus the cells of
+/// at most a few features per thread. A feature is only tessellated
+/// once every feature more than that far before it has been inserted,
+/// so a slow feature holds the others back rather than letting their
+/// cells pile up behind it.
+///
+/// Values aren't spilled to disk as they arrive. Each distinct value
+/// is held once, and memory peaks when the map is written, which
+/// needs the whole map and the whole LuT at once anyway, so a spill
+/// would only move the values out of memory until then.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn gen_world<I>(
+    features: I,
+    opts: WorldOptions<'_>,
+    progress: Option<ProgressFn<'_>>,
+) -> Result<World>
+where
+    I: IntoIterator<Item = geojson::Result<Feature>>,
+    I::IntoIter: Send,
+{
+    // Values are only held here, and laid out as the LuT at the end,
+    // so that large ones aren't stored twice.
+    let mut lut_indices: HashMap<String, u16> = HashMap::new();
+    let mut world = build_world(features, opts, progress, |_, value| {
+        let lut_len = lut_indices.len();
+        match lut_indices.entry(value) {
+            Entry::Occupied(entry) => Ok(*entry.get()),
+            Entry::Vacant(entry) => {
+                let lut_idx = u16::try_from(lut_len).map_err(|_| Error::TooManyValues {
+                    max: usize::from(u16::MAX) + 1,
+                    found: lut_len + 1,
+                })?;
+                Ok(*entry.insert(lut_idx))
+            }
+        }
+    })?;
+    world.lut = vec![String::new(); lut_indices.len()];
+    for (val, lut_idx) in lut_indices {
+        world.lut[usize::from(lut_idx)] = val;
+    }
+    Ok(world)
+}
+
+/// Tessellates `features` as for [`gen_world`], but stores the index
+/// of each cell's feature in `features` rather than its value, e.g.
+/// to join cells back to the source data.
+///
+/// There's no LuT, so write the map with [`write_map`]. Feature
+/// values are still computed, as the whole properties object, but
+/// only to report empty features.
+///
+/// [`write_map`]: crate::write_map
+#[cfg(not(target_arch = "wasm32"))]
+pub fn gen_world_indices<I>(
+    features: I,
+    opts: WorldOptions<'_>,
+    progress: Option<ProgressFn<'_>>,
+) -> Result<World<u32>>
+where
+    I: IntoIterator<Item = geojson::Result<Feature>>,
+    I::IntoIter: Send,
+{
+    let opts = WorldOptions {
+        property: None,
+        ..opts
+    };
+    build_world(features, opts, progress, |idx, _| {
+        u32::try_from(idx).map_err(|_| Error::TooManyValues {
+            max: u32::MAX as usize + 1,
+            found: idx + 1,
+        })
+    })
+}
+
+/// Keeps tessellation within a fixed number of features of insertion,
+/// so that the inserter only has to park that many out of order.
+#[cfg(not(target_arch = "wasm32"))]
+struct Window {
+    /// The next feature to insert, and whether insertion has stopped.
+    state: Mutex<(usize, bool)>,
+    advanced: Condvar,
+    size: usize,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Window {
+    fn new(size: usize) -> Self {
+        Window {
+            state: Mutex::new((0, false)),
+            advanced: Condvar::new(),
+            size,
+        }
+    }
+
+    /// Blocks until feature `idx` is within the window, returning
+    /// false if the window was closed instead.
+    fn enter(&self, idx: usize) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while idx >= state.0 + self.size && !state.1 {
+            state = self
+                .advanced
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        !state.1
+    }
+
+    fn advance(&self, next: usize) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).0 = next;
+        self.advanced.notify_all();
+    }
+
+    /// Releases every waiting feature, e.g. once a feature before
+    /// them has failed and insertion can't reach them.
+    fn close(&self) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).1 = true;
+        self.advanced.notify_all();
+    }
+}
+
+/// Closes a [`Window`] when dropped, however the inserter exits.
+#[cfg(not(target_arch = "wasm32"))]
+struct CloseOnDrop<'a>(&'a Window);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for CloseOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Tessellates `features` in parallel, inserting each one's cells
+/// into a map in feature order with the value `value_of` returns for
+/// the feature's index and value.
+///
+/// The returned world's LuT is left empty for the caller to fill.
+#[cfg(not(target_arch = "wasm32"))]
+fn build_world<I, V, F>(
+    features: I,
+    opts: WorldOptions<'_>,
+    progress: Option<ProgressFn<'_>>,
+    mut value_of: F,
+) -> Result<World<V>>
+where
+    I: IntoIterator<Item = geojson::Result<Feature>>,
+    I::IntoIter: Send,
+    V: Copy + Ord + Send,
+    F: FnMut(usize, String) -> Result<V> + Send,
+{
+    let (sender, rx) = mpsc::sync_channel::<(usize, Result<(String, Vec<CellIndex>)>)>(
+        2 * rayon::current_num_threads(),
+    );
+    let window = Window::new(4 * rayon::current_num_threads());
+    let features = features.into_iter();
+    let total = match features.size_hint() {
+        (lower, Some(upper)) if lower == upper => Some(lower),
+        _ => None,
+    };
+    let report = |phase, done| {
+        if let Some(progress) = progress {
+            progress(Progress { phase, done, total });
+        }
+    };
+    let tessellated_count = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        let window = &window;
+        let inserter = scope.spawn(move || -> Result<_> {
+            let _close = CloseOnDrop(window);
+            let mut world_map: HexTreeMap<V> = HexTreeMap::new();
+            let mut empty = Vec::new();
+            let mut failed = Vec::new();
+            let mut overlaps = BTreeMap::new();
+            // Features finish in arbitrary order; park each one here
+            // until every feature before it has been inserted. The
+            // window keeps this to at most `window.size` features.
+            let mut pending = BTreeMap::new();
+            let mut next_feature = 0;
+            for (feature_idx, tessellated) in rx {
+                pending.insert(feature_idx, tessellated);
+                while let Some(tessellated) = pending.remove(&next_feature) {
+                    let feature_idx = next_feature;
+                    next_feature += 1;
+                    window.advance(next_feature);
+                    report(Phase::Insert, next_feature);
+                    let (value, cells) = match tessellated {
+                        Ok(tessellated) => tessellated,
+                        Err(e @ Error::Feature { .. }) => {
+                            failed.push(e);
+                            continue;
+                        }
+                        Err(e) => {
+                            failed.push(Error::Feature {
+                                idx: feature_idx,
+                                reason: e.to_string(),
+                            });
+                            continue;
+                        }
+                    };
+                    // Skip these rather than giving them a value
+                    // nothing points to.
+                    if cells.is_empty() {
+                        empty.push((feature_idx, value));
+                        continue;
+                    }
+                    let value = value_of(feature_idx, value)?;
+                    let _span = info_span!("insert", idx = feature_idx).entered();
+                    for cell in cells {
+                        let claimed = Cell::from_raw(cell.into())
+                            .map_err(|e| Error::CellParse(e.to_string()))?;
+                        for (shared, existing) in
+                            opts.conflict.insert(&mut world_map, claimed, value)
+                        {
+                            let shared = CellIndex::try_from(shared.into_raw())
+                                .map_err(|e| Error::CellParse(e.to_string()))?;
+                            if opts.conflict == ConflictPolicy::Error {
+                                return Err(Error::Feature {
+                                    idx: feature_idx,
+                                    reason: format!("overlaps an earlier feature at {shared}"),
+                                });
+                            }
+                            *overlaps.entry((existing, value)).or_default() +=
+                                shared.children_count(opts.resolution);
+                        }
+                    }
+                }
+            }
+            Ok(World {
+                map: world_map,
+                lut: Vec::new(),
+                empty,
+                failed,
+                overlaps,
+                feature_count: next_feature,
+            })
+        });
+
+        // Tessellate on the calling thread so that the work runs in
+        // whatever rayon pool the caller installed. Numbering features
+        // before bridging keeps their indices in input order.
+        let tessellated = features.enumerate().par_bridge().try_for_each_with(
+            sender,
+            |sender, (feature_idx, feature)| {
+                // Close the window on failure, as the features after this
+                // one would otherwise wait for it forever.
+                let sent = (|| {
+                    if opts
+                        .cancel
+                        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+                    {
+                        return Err(Error::Cancelled);
+                    }
+                    // Closed once a feature before this one has failed,
+                    // in which case that failure is the one to report.
+                    if !window.enter(feature_idx) {
+                        return Ok(());
+                    }
+                    fn work_fun(
+                        idx: usize,
+                        feature: Feature,
+                        opts: WorldOptions<'_>,
+                    ) -> Result<(String, Vec<CellIndex>)> {
+                        let label = feature_label(&feature, opts.id_property);
+                        let tessellate = || -> Result<(String, Vec<CellIndex>)> {
+                            let (_, properties, cells) =
+                                to_cells(idx, feature, opts.resolution, opts.containment)?;
+                            let mut cells = dedup_cells(cells)?;
+                            if opts.compact {
+                                cells =
+                                    info_span!("compact", idx).in_scope(|| compact_cells(cells))?;
+                            }
+                            let value = feature_value(idx, properties, opts.property)?;
+                            Ok((value, cells))
+                        };
+                        tessellate().map_err(|e| {
+                            let reason = match e {
+                                Error::Feature { reason, .. } => reason,
+                                e => e.to_string(),
+                            };
+                            Error::Feature {
+                                idx,
+                                reason: format!("{label}: {reason}"),
+                            }
+                        })
+                    }
+                    let tessellated = work_fun(feature_idx, feature?, opts);
+                    report(
+                        Phase::Tessellate,
+                        tessellated_count.fetch_add(1, Ordering::Relaxed) + 1,
+                    );
+                    // Failures still go to the inserter when keeping
+                    // going, so that it can move past them.
+                    if tessellated.is_err() && !opts.keep_going {
+                        return tessellated.map(|_| ());
+                    }
+                    sender
+                        .send((feature_idx, tessellated))
+                        .map_err(|_| Error::Thread("inserter hung up".to_string()))
+                })();
+                if sent.is_err() {
+                    window.close();
+                }
+                sent
+            },
+        );
+
+        // An insertion error makes the tessellation's sends fail, so
+        // check it first to report the root cause.
+        let built = inserter
+            .join()
+            .map_err(|join_err| Error::Thread(format!("join {:?}", join_err)))??;
+        tessellated?;
+        Ok(built)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_cells_are_caught() {
+        let ancestor = LatLng::new(10.0, 10.0).unwrap().to_cell(Resolution::Five);
+        let cell = ancestor.center_child(Resolution::Seven).unwrap();
+        let sibling = ancestor.grid_disk::<Vec<_>>(1)[1];
+        assert!(check_not_nested(&[ancestor, sibling]).is_ok());
+        let err = check_not_nested(&[ancestor, sibling, cell]).unwrap_err();
+        assert!(
+            matches!(err, Error::NestedCells { cell: c, ancestor: a } if c == cell && a == ancestor),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Region lookup maps backed by an on-disk [`DiskTree`].
+//!
+//! A map file starts with the [`MAGIC`] bytes, a format version byte,
+//! and a [`Header`]. Next comes a disktree whose values are usually
+//! `u16` indices into a look-up table (LuT) of strings, followed by
+//! the bincode-serialized LuT, a table of each LuT entry's position,
+//! and a trailer holding the CRC32 of both and their positions. The
+//! LuT can optionally be gzipped, flagged in the header, or kept in a
+//! file of its own, see [`write_split_region_map`]. Whole map files
+//! can be gzip or zstd compressed too, see [`Compression`]. Maps
+//! written by earlier format versions, back to those from before the
+//! header, are still read.
+//!
+//! Built for `wasm32`, the crate leaves out what needs threads, memory
+//! mapping or native code: `gen_world`, `gen_world_indices`,
+//! `RegionMap::open_mmap`, `RegionMap::lookup_shared` and
+//! `RegionMap::lookup_many`. zstd compressed maps fail with
+//! [`Error::ZstdUnsupported`] there.
+//!
+//! The `lwreg` binary and the crates only it uses are behind the
+//! default `cli` feature, so build the library on its own, for Python
+//! or wasm, with `--no-default-features`.
+//!
+//! [`DiskTree`]: hextree::disktree::DiskTree
+
+mod error;
+mod format;
+mod generate;
+#[cfg(feature = "python")]
+mod python;
+mod region_map;
+mod topojson;
+mod verify;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use error::{Error, Result};
+pub use format::{
+    write_cell_map, write_map, write_region_map, write_split_region_map, Compression, Header,
+    ValueWidth, FORMAT_VERSION, H3O_VERSION, HEXTREE_VERSION, MAGIC,
+};
+pub use generate::{
+    cap_compaction, coarsen_cells, compact_cells, dedup_cells, region_contains, to_cells,
+    ConflictPolicy, Phase, Progress, ProgressFn, World, WorldOptions,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use generate::{gen_world, gen_world_indices};
+pub use region_map::{MapFile, RegionMap};
+pub use topojson::topojson_to_features;
+pub use verify::verify;
@@ -1,38 +1,160 @@
-#[cfg(not(target_env = "msvc"))]
+#[cfg(not(any(target_env = "msvc", target_arch = "wasm32")))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
 use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt};
-use clap::Parser;
-use flate2::read::GzDecoder;
-use geojson::{Feature, FeatureCollection, GeoJson, JsonObject};
+use clap::{CommandFactory, Parser};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use geojson::{Feature, FeatureCollection, FeatureReader, GeoJson, JsonObject};
 use h3o::{
-    geom::{Geometry, ToCells},
-    CellIndex, Resolution,
+    geom::{ContainmentMode, Geometry, PolyfillConfig, ToCells, ToGeo},
+    CellIndex, LatLng, Resolution,
 };
-use hextree::{disktree::DiskTree, Cell, HexTreeMap};
+use hextree::{Cell, HexTreeMap};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::warn;
+use lwreg::{
+    cap_compaction, coarsen_cells, gen_world, gen_world_indices, write_map, write_region_map,
+    write_split_region_map, Compression, ConflictPolicy, Phase, Progress, RegionMap, ValueWidth,
+    WorldOptions,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
 use serde_json::Value;
 use std::{
+    collections::{BTreeMap, HashMap},
     fs::File,
-    io::{Seek, SeekFrom},
-    path::PathBuf,
-    sync::mpsc,
-    thread,
+    io::{self, BufRead, BufReader, BufWriter, Cursor, IsTerminal, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
 };
-#[cfg(not(target_env = "msvc"))]
+#[cfg(not(any(target_env = "msvc", target_arch = "wasm32")))]
 use tikv_jemallocator::Jemalloc;
+use tracing::info_span;
+use tracing_subscriber::fmt::format::FmtSpan;
 
 #[derive(Debug, clap::Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(about = "Helium DiskTreeMap multitool")]
-enum Cli {
+struct Cli {
+    /// Log progress to stderr, repeat for more detail
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Cap the number of threads used for parallel work, which
+    /// defaults to one per core
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+    /// For generate and gen-world, read and check the inputs and
+    /// report what would be built without writing anything
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Print how long each phase of generation takes to stderr: input
+    /// parsing, tessellation, compaction, insertion, and writing
+    #[arg(long, global = true)]
+    trace: bool,
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+impl Cli {
+    fn log_level(&self) -> log::LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => log::LevelFilter::Error,
+            (false, 0) => log::LevelFilter::Warn,
+            (false, 1) => log::LevelFilter::Info,
+            (false, _) => log::LevelFilter::Debug,
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Cmd {
     /// Generate a disktree from source h3idz sets
+    ///
+    /// LuT entries are in byte order of region name, then of each
+    /// set's canonical path, so the same sets always give an identical
+    /// map however they're listed.
     Generate {
-        /// Output file
+        /// Truncate input cells to this resolution, then compact. With
+        /// `auto`, cells are kept as they are and the map takes the
+        /// resolution they share, which is an error if they don't,
+        /// unless --mixed-res, when it takes the finest
+        #[arg(short, long)]
+        resolution: Option<ResolutionArg>,
+        /// Don't compact cells truncated by --resolution. The map then
+        /// holds every cell at that resolution, which can make it many
+        /// times larger
+        #[arg(long)]
+        no_compact: bool,
+        /// Split compacted cells coarser than this resolution back into
+        /// their children at it, so that no stored cell is coarser. Must
+        /// not be finer than the map's resolution
+        #[arg(long, value_name = "RES")]
+        max_compact_res: Option<Resolution>,
+        /// How to handle cells present in more than one set
+        #[arg(value_enum, default_value_t = Conflict::Warn, long)]
+        conflict: Conflict,
+        /// Input set format, inferred from each file's extension when
+        /// omitted
+        #[arg(value_enum, long)]
+        format: Option<SetFormat>,
+        /// TOML file of `"path" = "region"` entries naming input sets,
+        /// with paths relative to the file. Sets not listed are named
+        /// after their file name up to the first '.'
+        #[arg(long)]
+        names: Option<PathBuf>,
+        /// Region name for a set read from stdin, given as -
+        #[arg(long)]
+        stdin_name: Option<String>,
+        /// Warn rather than fail when more than one set has the same
+        /// name. Each still gets its own LuT entry
+        #[arg(long)]
+        allow_dup_names: bool,
+        /// Warn rather than fail when input cells aren't all the same
+        /// resolution. Not checked with a --resolution other than
+        /// `auto`, which truncates them to one
+        #[arg(long, visible_alias = "allow-mixed")]
+        mixed_res: bool,
+        /// With `cell`, each input is newline-separated `<cell>,<value>`
+        /// hex pairs and the map stores each value cell itself. The
+        /// other options then don't apply. With `index`, the map
+        /// stores each set's index, printed to stderr, and no LuT
+        #[arg(value_enum, default_value_t = ValueType::Region, long)]
+        value_type: ValueType,
+        /// Print each region's cell count at the map's resolution
+        /// and as stored after compaction to stderr
+        #[arg(long)]
+        stats: bool,
+        /// Gzip the LuT. Shrinks maps with many or large values, but
+        /// readers then load the whole LuT on open
+        #[arg(long)]
+        gzip_lut: bool,
+        /// Compress the whole map file. Readers detect this and
+        /// decompress the map into memory on open
+        #[arg(value_enum, default_value_t = Compress::None, long)]
+        compress: Compress,
+        /// Write the disktree to <OUT>.disktree and the LuT to
+        /// <OUT>.lut, so that the disktree can be cached apart from
+        /// values that change more often. Lookups load the .lut next
+        /// to the map
+        #[arg(long, conflicts_with = "compress")]
+        split: bool,
+        /// Width to store LuT indices in, the narrowest that fits the
+        /// regions if omitted. A wider one leaves room for regions
+        /// appended later
+        #[arg(value_enum, long)]
+        value_width: Option<Width>,
+        /// Overwrite the output if it exists, which is otherwise an
+        /// error
+        #[arg(long)]
+        force: bool,
+        /// Output file, or - to write to stdout
         out: PathBuf,
-        /// Input h3idz files
+        /// Input cell sets, or - for stdin
         sets: Vec<PathBuf>,
     },
 
@@ -41,208 +163,2696 @@ enum Cli {
         /// Resolution to use for h3 cells
         #[arg(default_value_t = Resolution::Seven, short, long)]
         resolution: Resolution,
-        /// Output file
+        /// Which cells along a feature's boundary to include
+        #[arg(value_enum, default_value_t = Containment::Center, long)]
+        containment: Containment,
+        /// Store only this property of each feature rather than all of
+        /// its properties as JSON
+        #[arg(long)]
+        property: Option<String>,
+        /// Property naming each feature in errors, the first property
+        /// by name if omitted
+        #[arg(long, value_name = "KEY")]
+        id_property: Option<String>,
+        /// Store every cell at --resolution instead of compacting. Large
+        /// regions are mostly interior cells, so this makes the map
+        /// several times larger
+        #[arg(long)]
+        no_compact: bool,
+        /// Split compacted cells coarser than this resolution back into
+        /// their children at it, so that no stored cell is coarser. Must
+        /// not be finer than the map's resolution
+        #[arg(long, value_name = "RES")]
+        max_compact_res: Option<Resolution>,
+        /// Skip features that fail to tessellate instead of aborting,
+        /// still writing the map but exiting non-zero at the end
+        #[arg(long)]
+        keep_going: bool,
+        /// How to handle cells claimed by more than one feature. Unless
+        /// that's `error`, overlaps are reported, naming the features
+        /// that overlap most
+        #[arg(value_enum, default_value_t = Conflict::Warn, long)]
+        conflict: Conflict,
+        /// With `index`, store the index of each cell's feature in the
+        /// input instead of its value, and no LuT, for joining cells
+        /// back to the source data
+        #[arg(value_enum, default_value_t = ValueType::Region, long, visible_alias = "value")]
+        value_type: ValueType,
+        /// Print each region's cell count at the map's resolution
+        /// and as stored after compaction to stderr
+        #[arg(long)]
+        stats: bool,
+        /// Warn about regions that split into more than this many
+        /// groups of adjacent cells, 10 if no count is given, or that
+        /// cover fewer than 7 cells. Either suggests --resolution is
+        /// too coarse for them
+        #[arg(long, value_name = "COMPONENTS", num_args = 0..=1, default_missing_value = "10")]
+        warn_fragments: Option<usize>,
+        /// Gzip the LuT. Shrinks maps with many or large values, but
+        /// readers then load the whole LuT on open
+        #[arg(long)]
+        gzip_lut: bool,
+        /// Compress the whole map file. Readers detect this and
+        /// decompress the map into memory on open
+        #[arg(value_enum, default_value_t = Compress::None, long)]
+        compress: Compress,
+        /// Write the disktree to <OUT>.disktree and the LuT to
+        /// <OUT>.lut, so that the disktree can be cached apart from
+        /// values that change more often. Lookups load the .lut next
+        /// to the map
+        #[arg(long, conflicts_with = "compress")]
+        split: bool,
+        /// Width to store LuT indices in, the narrowest that fits the
+        /// regions if omitted. A wider one leaves room for regions
+        /// appended later
+        #[arg(value_enum, long)]
+        value_width: Option<Width>,
+        /// Overwrite the output if it exists, which is otherwise an
+        /// error
+        #[arg(long)]
+        force: bool,
+        /// Input format, inferred from the extension when omitted:
+        /// TopoJSON for .topojson, else GeoJSON
+        #[arg(value_enum, long)]
+        format: Option<WorldFormat>,
+        /// Output file, or - to write to stdout
         out: PathBuf,
-        /// Input h3idz files
+        /// Input GeoJSON feature collection or TopoJSON topology,
+        /// optionally gzipped
         world: PathBuf,
     },
 
-    /// Lookup target H3 cell
+    /// Add sets to an existing disktree, rewriting it in place
+    ///
+    /// A map with a split LuT stays split, with its .lut rewritten
+    /// alongside it.
+    Append {
+        /// How to handle cells already in the map or in more than one
+        /// new set
+        #[arg(value_enum, default_value_t = Conflict::Warn, long)]
+        conflict: Conflict,
+        /// Don't compact cells truncated to the map's resolution, as
+        /// for a map generated with --no-compact
+        #[arg(long)]
+        no_compact: bool,
+        /// Input set format, inferred from each file's extension when
+        /// omitted
+        #[arg(value_enum, long)]
+        format: Option<SetFormat>,
+        /// Region name for a set read from stdin, given as -
+        #[arg(long)]
+        stdin_name: Option<String>,
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Input cell sets, or - for stdin
+        sets: Vec<PathBuf>,
+    },
+
+    /// Merge several disktrees into one
+    Merge {
+        /// How to handle cells present in more than one map
+        #[arg(value_enum, default_value_t = Conflict::Warn, long)]
+        conflict: Conflict,
+        /// Gzip the LuT. Shrinks maps with many or large values, but
+        /// readers then load the whole LuT on open
+        #[arg(long)]
+        gzip_lut: bool,
+        /// Output file, or - to write to stdout
+        out: PathBuf,
+        /// Input maps, later maps win conflicts under `last`
+        maps: Vec<PathBuf>,
+    },
+
+    /// Write a new map holding only the given regions of a map
+    ///
+    /// The new map keeps the original's resolution, LuT gzipping and
+    /// compression, and its LuT holds just the kept regions, in their
+    /// original order.
+    Subset {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Output file, or - to write to stdout
+        out: PathBuf,
+        /// LuT values of the regions to keep
+        #[arg(required = true)]
+        regions: Vec<String>,
+    },
+
+    /// Lookup target H3 cells
+    ///
+    /// A map written with --compress is recognized by its gzip or zstd
+    /// magic bytes and decompressed into memory before any lookups.
+    ///
+    /// Exits 2 if any index has no entry, and 1 on errors such as an
+    /// unreadable map or a malformed index.
     Lookup {
         /// On disk HexTreeMap
         map: PathBuf,
+        /// Target h3 indices, read newline-separated from stdin when
+        /// omitted
+        idxs: Vec<String>,
+        /// Print a JSON object with the cell and its value, the same
+        /// as --format json
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+        /// How to print results
+        #[arg(value_enum, default_value_t = LookupFormat::Text, long)]
+        format: LookupFormat,
+        /// Also print the stored cell each lookup matched, which can be
+        /// coarser than the query in a compacted map
+        #[arg(long)]
+        show_cell: bool,
+        /// Base of the h3 indices. A 0x prefix always means hex
+        #[arg(value_enum, default_value_t = IdxBase::Hex, long)]
+        base: IdxBase,
+        /// What the map stores, as given to generate or gen-world
+        #[arg(value_enum, default_value_t = ValueType::Region, long)]
+        value_type: ValueType,
+        /// Print the stored value as a decimal number rather than
+        /// resolving it: a region's LuT index, or a value cell's raw
+        /// index. Helps diagnose LuT mismatches
+        #[arg(long)]
+        raw: bool,
+        /// Bring each cell to this resolution before looking it up,
+        /// taking the parent of finer cells and failing for coarser
+        /// ones, rather than leaving it to the map's own matching
+        #[arg(long, value_name = "RES")]
+        at_res: Option<Resolution>,
+    },
+
+    /// Lookup one H3 cell in several maps, printing each map's value
+    /// labeled by its file name
+    ///
+    /// Exits 2 if any map has no entry, and 1 on errors.
+    LookupMulti {
+        /// On disk HexTreeMaps, e.g. layers of the same area
+        #[arg(required = true)]
+        maps: Vec<PathBuf>,
         /// Target h3 index
         idx: String,
+        /// Base of the h3 index. A 0x prefix always means hex
+        #[arg(value_enum, default_value_t = IdxBase::Hex, long)]
+        base: IdxBase,
+    },
+
+    /// Open a map once and look up cells typed one per line, until
+    /// `:q` or end of input
+    Repl {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Base of the h3 indices. A 0x prefix always means hex
+        #[arg(value_enum, default_value_t = IdxBase::Hex, long)]
+        base: IdxBase,
+    },
+
+    /// Lookup the cell containing a coordinate
+    ///
+    /// Exits 2 if the map has no entry for it, and 1 on errors.
+    #[command(allow_negative_numbers = true)]
+    LookupCoord {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Latitude in degrees
+        lat: f64,
+        /// Longitude in degrees
+        lng: f64,
+        /// Print a JSON object with the cell and its value
+        #[arg(long)]
+        json: bool,
+        /// Also print the stored cell the coordinate matched. The
+        /// coarser it is, the further a border could be from the
+        /// coordinate without the map telling them apart
+        #[arg(long)]
+        show_cell: bool,
+        /// Check the coordinate against the polygons of the found
+        /// region in this gen-world input, exiting 2 if it's outside
+        /// them, e.g. in a cell that straddles a border
+        #[arg(long, value_name = "WORLD")]
+        verify: Option<PathBuf>,
+        /// Property the map's regions were taken from, as given to
+        /// gen-world, to match the found region to features in
+        /// --verify's input
+        #[arg(long, requires = "verify")]
+        property: Option<String>,
+    },
+
+    /// Lookup each row of a CSV of coordinates, printing the CSV with
+    /// an appended region column
+    ///
+    /// Exits 2 if any row has no entry, and 1 on errors.
+    LookupCsv {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Input CSV, with a header row
+        csv: PathBuf,
+        /// Name of the latitude column
+        #[arg(default_value = "lat", long)]
+        lat_col: String,
+        /// Name of the longitude column
+        #[arg(default_value = "lng", long)]
+        lng_col: String,
+    },
+
+    /// Print the regions found in a cell's k-ring, with cell counts
+    Ring {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Center h3 index
+        idx: String,
+        /// Ring distance
+        #[arg(default_value_t = 1, short)]
+        k: u32,
+        /// Base of the h3 index. A 0x prefix always means hex
+        #[arg(value_enum, default_value_t = IdxBase::Hex, long)]
+        base: IdxBase,
+    },
+
+    /// Print the regions found in a lat/lng bounding box, with cell
+    /// counts
+    #[command(allow_negative_numbers = true)]
+    Bbox {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Southern edge in degrees
+        min_lat: f64,
+        /// Western edge in degrees
+        min_lng: f64,
+        /// Northern edge in degrees
+        max_lat: f64,
+        /// Eastern edge in degrees
+        max_lng: f64,
+        /// Which cells along the box's edge to include
+        #[arg(value_enum, default_value_t = Containment::Overlapping, long)]
+        containment: Containment,
+    },
+
+    /// Print the regions overlapped by GeoJSON geometry, with cell
+    /// counts
+    Query {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// GeoJSON geometry, feature, or feature collection. Cells in
+        /// more than one of its geometries are only counted once
+        geojson: PathBuf,
+        /// Which cells along the geometry's boundary to include
+        #[arg(value_enum, default_value_t = Containment::Overlapping, long)]
+        containment: Containment,
+    },
+
+    /// Print every cell belonging to a region
+    Dump {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Region name, exactly as stored in the LuT
+        region: String,
+        /// Skip the region's first M cells. Cells always come in the
+        /// same order, so pages are stable across runs
+        #[arg(long, value_name = "M", default_value_t = 0)]
+        offset: usize,
+        /// Print at most N cells
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+    },
+
+    /// Print N stored cells picked at random, each with its region,
+    /// to smoke-test a map
+    Sample {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Number of cells to pick
+        #[arg(default_value_t = 10)]
+        n: usize,
+        /// Seed the picks so that runs are repeatable
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+
+    /// Print the number of stored cells per region
+    Count {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Also count cells at the map's resolution, expanding
+        /// compacted parents into their children
+        #[arg(long)]
+        expand: bool,
+        /// Also sum the area each region's cells cover, in km²
+        #[arg(long)]
+        area: bool,
+    },
+
+    /// Write each region's cell count and area as CSV
+    ///
+    /// Rows are `region,cell_count,area_km2` in LuT order, after a
+    /// header row and followed by a `total` row. Counts are cells at
+    /// the map's resolution, expanding compacted parents.
+    Histogram {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Output CSV file
+        out: PathBuf,
+    },
+
+    /// Print each cell at a coarser resolution with the regions
+    /// beneath it and how many cells at the map's resolution each has
+    Aggregate {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Resolution to roll stored cells up to. Must not be finer
+        /// than the map's resolution
+        resolution: Resolution,
+    },
+
+    /// Export a region's cells as a GeoJSON FeatureCollection
+    Export {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Region name, exactly as stored in the LuT
+        region: String,
+        /// Output GeoJSON file
+        out: PathBuf,
+        /// Dissolve adjacent cells into a single MultiPolygon instead
+        /// of one polygon per cell
+        #[arg(long)]
+        compact: bool,
+    },
+
+    /// Write a region's cells as an h3idz set, as generate reads
+    ToH3idz {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Region name, exactly as stored in the LuT
+        region: String,
+        /// Output h3idz file
+        out: PathBuf,
+        /// Uncompact cells coarser than this resolution into their
+        /// children at it. It's an error for the region to have finer
+        /// cells
+        #[arg(short, long)]
+        resolution: Option<Resolution>,
     },
+
+    /// Compare two disktrees cell by cell
+    Diff {
+        /// Old map
+        a: PathBuf,
+        /// New map
+        b: PathBuf,
+        /// Print each differing cell, not just counts
+        #[arg(long)]
+        detail: bool,
+    },
+
+    /// Check a map file's integrity, exiting non-zero on failure
+    Verify {
+        /// On disk HexTreeMap
+        map: PathBuf,
+    },
+
+    /// Print map metadata and LuT entries
+    Info {
+        /// On disk HexTreeMap
+        map: PathBuf,
+        /// Print metadata as JSON
+        #[arg(long)]
+        json: bool,
+        /// Pretty-print JSON, including LuT entries that are JSON
+        #[arg(long)]
+        pretty: bool,
+    },
+
+    /// Answer lookups over HTTP with JSON, at `/lookup?cell=<hex>` or
+    /// `/lookup?lat=<deg>&lng=<deg>`
+    #[cfg(feature = "serve")]
+    Serve {
+        /// On disk HexTreeMap. It's memory-mapped, so it must not be
+        /// modified while being served
+        #[arg(long)]
+        map: PathBuf,
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+    },
+
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        shell: clap_complete::Shell,
+    },
+}
+
+/// Cell inclusion rule for tessellation, see [`ContainmentMode`].
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Containment {
+    /// Cells whose center is inside the polygon
+    Center,
+    /// Cells that intersect the polygon at all
+    Overlapping,
+    /// Like overlapping, but guarantees even tiny polygons get a cell
+    Covers,
 }
 
-fn to_cells(
-    idx: u8,
-    feature: Feature,
-    resolution: Resolution,
-) -> Result<(u8, JsonObject, Vec<CellIndex>)> {
-    println!("generating {:?}", feature.properties);
-    let start = std::time::Instant::now();
-    let properties = feature
-        .properties
-        .ok_or_else(|| anyhow!("no properties for feature {idx}"))?;
-    let geometry = feature
-        .geometry
-        .ok_or_else(|| anyhow!("feature {idx} missing geometry"))?;
-    let geometry = Geometry::try_from(&geometry)?;
-    let cells = geometry.to_cells(resolution).collect();
-    println!("  generated {:?} in {:?}", properties, start.elapsed());
-    Ok((idx, properties, cells))
+impl From<Containment> for ContainmentMode {
+    fn from(containment: Containment) -> Self {
+        match containment {
+            Containment::Center => ContainmentMode::ContainsCentroid,
+            Containment::Overlapping => ContainmentMode::IntersectsBoundary,
+            Containment::Covers => ContainmentMode::Covers,
+        }
+    }
 }
 
-fn dedup_cells(mut cells: Vec<CellIndex>) -> Result<Vec<CellIndex>> {
-    cells.sort_unstable();
-    cells.dedup();
-    Ok(cells)
+/// What to do with a cell claimed more than once, see
+/// [`ConflictPolicy`].
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Conflict {
+    /// Keep the last claim
+    Last,
+    /// Keep the first claim
+    First,
+    /// Abort
+    Error,
+    /// Keep the last claim and report how many cells conflicted
+    Warn,
 }
 
-fn compact_cells(cells: Vec<CellIndex>) -> Result<Vec<CellIndex>> {
-    let compacted = CellIndex::compact(cells)?;
-    Ok(compacted.collect())
+impl From<Conflict> for ConflictPolicy {
+    fn from(conflict: Conflict) -> Self {
+        match conflict {
+            Conflict::Last => ConflictPolicy::Last,
+            Conflict::First => ConflictPolicy::First,
+            Conflict::Error => ConflictPolicy::Error,
+            Conflict::Warn => ConflictPolicy::Warn,
+        }
+    }
 }
 
-impl Cli {
-    fn run(self) -> Result<()> {
-        match self {
-            Cli::GenWorld {
-                resolution,
-                out,
-                world,
-            } => {
-                let mut disktree_file = File::create(out)?;
-                let feature_collection = {
-                    let geojson_file = File::open(world)?;
-                    let geojson = GeoJson::from_reader(geojson_file)?;
-                    FeatureCollection::try_from(geojson)?
-                };
+/// Whole-file compression for written maps, see [`Compression`].
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Compress {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl From<Compress> for Compression {
+    fn from(compress: Compress) -> Self {
+        match compress {
+            Compress::None => Compression::None,
+            Compress::Gzip => Compression::Gzip,
+            Compress::Zstd => Compression::Zstd,
+        }
+    }
+}
 
-                let mut world_map: HexTreeMap<u8> = HexTreeMap::new();
-                let mut property_lut: Vec<(u8, String)> = Vec::new();
+/// Width of stored LuT indices, see [`ValueWidth`].
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Width {
+    U8,
+    U16,
+    U32,
+}
 
-                let (sender, rx) = mpsc::channel::<(u8, String, Vec<CellIndex>)>();
+impl From<Width> for ValueWidth {
+    fn from(width: Width) -> Self {
+        match width {
+            Width::U8 => ValueWidth::U8,
+            Width::U16 => ValueWidth::U16,
+            Width::U32 => ValueWidth::U32,
+        }
+    }
+}
 
-                let thread_handle = thread::spawn(move || {
-                    feature_collection
-                        .features
-                        .into_par_iter()
-                        .enumerate()
-                        .try_for_each_with(
-                            (sender.clone(), resolution),
-                            |(sender, resolution), (lut_idx, feature)| {
-                                fn work_fun(
-                                    idx: usize,
-                                    feature: Feature,
-                                    res: Resolution,
-                                    tx: &mut mpsc::Sender<(u8, String, Vec<CellIndex>)>,
-                                ) -> Result<()> {
-                                    let idx = u8::try_from(idx)?;
-                                    let (_, properties, cells) = to_cells(idx, feature, res)?;
-                                    let cells = dedup_cells(cells)?;
-                                    let cells = compact_cells(cells)?;
-                                    let properties = Value::Object(properties);
-                                    tx.send((idx, properties.to_string(), cells))?;
-                                    Ok(())
-                                }
-                                work_fun(lut_idx, feature, *resolution, sender)
-                            },
-                        )
-                });
+/// What a map stores for each cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ValueType {
+    /// A region, as an index into the LuT
+    Region,
+    /// Another h3 cell, stored whole
+    Cell,
+    /// The index of the gen-world feature, or the generate input set
+    /// in name order, the cell came from, with no LuT
+    Index,
+}
 
-                while let Ok((lut_idx, properties, cells)) = rx.recv() {
-                    property_lut.push((lut_idx, properties));
-                    for cell in cells {
-                        let cell = Cell::from_raw(cell.into())?;
-                        world_map.insert(cell, lut_idx);
-                    }
+/// A `generate --resolution`: a resolution, or `auto` to infer it from
+/// the input cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResolutionArg {
+    Auto,
+    Res(Resolution),
+}
+
+impl std::str::FromStr for ResolutionArg {
+    type Err = <Resolution as std::str::FromStr>::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            res => res.parse().map(Self::Res),
+        }
+    }
+}
+
+/// Base of h3 indices given as arguments or typed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum IdxBase {
+    /// Hexadecimal, as h3 tools print them
+    Hex,
+    /// Decimal, as databases storing them as integers print them
+    Dec,
+}
+
+/// Returns `cell`'s parent at `res`, or `cell` itself if it's at
+/// `res` already. A coarser cell has no one cell at `res`.
+fn cell_at_res(cell: Cell, res: Resolution) -> Result<Cell> {
+    let res = u8::from(res);
+    if cell.res() == res {
+        return Ok(cell);
+    }
+    if cell.res() < res {
+        return Err(anyhow!(
+            "{:x} is res {}, too coarse to bring to res {res}",
+            cell.into_raw(),
+            cell.res()
+        ));
+    }
+    cell.to_parent(res)
+        .ok_or_else(|| anyhow!("no parent at res {res} for {:x}", cell.into_raw()))
+}
+
+/// Parses `idx` as an h3 index in `base`, returning it and its cell.
+fn parse_cell(idx: &str, base: IdxBase) -> Result<(u64, Cell)> {
+    let raw = match (idx.strip_prefix("0x"), base) {
+        (Some(hex), _) => u64::from_str_radix(hex, 16),
+        (None, IdxBase::Hex) => u64::from_str_radix(idx, 16),
+        (None, IdxBase::Dec) => idx.parse(),
+    }
+    .map_err(|e| anyhow!("can't parse {idx} as an h3 index: {e}"))?;
+    let cell = Cell::try_from(raw).map_err(|e| anyhow!("{idx} is not a valid cell: {e}"))?;
+    Ok((raw, cell))
+}
+
+/// Warns if the map behind `header` was written with different h3o or
+/// hextree versions than this binary, which could in principle change
+/// how it reads. Unknown versions aren't compared.
+fn warn_on_version_mismatch(header: &lwreg::Header) {
+    let differs =
+        |theirs: &str, ours: &str| theirs != ours && theirs != "unknown" && ours != "unknown";
+    if differs(&header.h3o_version, lwreg::H3O_VERSION)
+        || differs(&header.hextree_version, lwreg::HEXTREE_VERSION)
+    {
+        warn!(
+            "map was written with h3o {} and hextree {}, this binary has h3o {} and hextree {}",
+            header.h3o_version,
+            header.hextree_version,
+            lwreg::H3O_VERSION,
+            lwreg::HEXTREE_VERSION
+        );
+    }
+}
+
+/// How `lookup` prints results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LookupFormat {
+    /// Each value on its own line, skipping misses
+    Text,
+    /// A JSON object per line, skipping misses
+    Json,
+    /// A JSON object per line, misses included with a null value so
+    /// that there's a line per input. Misses then aren't an error
+    Jsonl,
+}
+
+/// Encoding of a `gen-world` input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum WorldFormat {
+    /// A GeoJSON feature collection
+    Geojson,
+    /// A TopoJSON topology, converted to features on load
+    Topojson,
+}
+
+impl WorldFormat {
+    /// Infers the format from `path`'s extension, ignoring a trailing
+    /// .gz, falling back to GeoJSON.
+    fn from_path(path: &Path) -> Self {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        if name.ends_with(".topojson") {
+            WorldFormat::Topojson
+        } else {
+            WorldFormat::Geojson
+        }
+    }
+}
+
+type Features = Box<dyn Iterator<Item = geojson::Result<Feature>> + Send>;
+
+/// Opens a `gen-world` input, gzipped or not, in `format` or as its
+/// extension says.
+///
+/// A GeoJSON document is streamed, so with `progress` its length is
+/// set to the file's size and its position follows the bytes read.
+fn read_world(
+    path: &Path,
+    format: Option<WorldFormat>,
+    progress: Option<&ProgressBar>,
+) -> Result<Features> {
+    let format = format.unwrap_or_else(|| WorldFormat::from_path(path));
+    let file = File::open(path)?;
+    let file: Box<dyn Read + Send> = match progress {
+        Some(progress) if format == WorldFormat::Geojson => {
+            progress.set_length(file.metadata()?.len());
+            Box::new(progress.wrap_read(file))
+        }
+        _ => Box::new(file),
+    };
+    let mut rdr = BufReader::new(file);
+    // Sniff the gzip magic rather than trusting the extension.
+    let rdr: Box<dyn Read + Send> = if rdr.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(GzDecoder::new(rdr)))
+    } else {
+        Box::new(rdr)
+    };
+    Ok(match format {
+        // Parsed as features are asked for, so the whole document is
+        // never in memory.
+        WorldFormat::Geojson => Box::new(FeatureReader::from_reader(rdr).features()),
+        // Arcs are shared between features, so a topology has to be
+        // read whole.
+        WorldFormat::Topojson => {
+            let _span = info_span!("parse").entered();
+            let topology = serde_json::from_reader(rdr)?;
+            let features = lwreg::topojson_to_features(&topology)?.features;
+            Box::new(features.into_iter().map(Ok))
+        }
+    })
+}
+
+/// Encoding of a `generate` input set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SetFormat {
+    /// Gzipped little-endian u64 cells
+    H3idz,
+    /// Newline-separated hex cells
+    Hex,
+    /// Newline-separated decimal cells
+    Dec,
+}
+
+impl SetFormat {
+    /// Infers the format from `path`'s extension, falling back to
+    /// h3idz.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("txt" | "csv") => SetFormat::Hex,
+            _ => SetFormat::H3idz,
+        }
+    }
+
+    /// Reads every cell in `set`.
+    fn read(self, set: &SetSource) -> Result<Vec<CellIndex>> {
+        match set {
+            SetSource::File(file) => self.read_from(file),
+            SetSource::Stdin(buf) => self.read_from(buf.as_slice()),
+        }
+    }
+
+    fn read_from<R: Read>(self, rdr: R) -> Result<Vec<CellIndex>> {
+        let mut cells = Vec::new();
+        let radix = match self {
+            SetFormat::H3idz => {
+                let mut rdr = GzDecoder::new(rdr);
+                while let Ok(entry) = rdr.read_u64::<LE>() {
+                    cells.push(CellIndex::try_from(entry)?);
                 }
+                return Ok(cells);
+            }
+            SetFormat::Hex => 16,
+            SetFormat::Dec => 10,
+        };
+        for line in BufReader::new(rdr).lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() {
+                cells.push(CellIndex::try_from(u64::from_str_radix(line, radix)?)?);
+            }
+        }
+        Ok(cells)
+    }
+}
 
-                thread_handle
-                    .join()
-                    .map_err(|join_err| anyhow!("thread join {:?}", join_err))
-                    .unwrap()?;
+/// Where an input set is read from.
+enum SetSource {
+    File(File),
+    /// Stdin, read up front so that sets can be decoded in parallel.
+    Stdin(Vec<u8>),
+}
 
-                world_map.to_disktree(&mut disktree_file, |wtr, &val| wtr.write_u8(val))?;
-                property_lut.sort_by_key(|(lut_idx, _)| *lut_idx);
-                let property_lut: Vec<String> = property_lut
-                    .into_iter()
-                    .map(|(_lut_idx, properties)| properties)
-                    .collect();
+/// Prints a looked-up value, either as-is or as a JSON object with
+/// its cell.
+///
+/// With `matched`, also prints the stored cell the lookup matched
+/// and its resolution.
+fn print_value(cell_idx: u64, val: &str, matched: Option<Cell>, json: bool) {
+    if json {
+        println!("{}", value_json(cell_idx, val, matched));
+    } else if let Some(matched) = matched {
+        println!("{val} ({:x} at res {})", matched.into_raw(), matched.res());
+    } else {
+        println!("{val}");
+    }
+}
 
-                // Append country LuT to end of `out` and write
-                // its position the end of the file.
-                let property_lut_pos = disktree_file.seek(SeekFrom::End(0))?;
-                bincode::serialize_into(&mut disktree_file, &property_lut)?;
-                disktree_file.write_u64::<LE>(property_lut_pos)?;
-            }
-
-            Cli::Generate { out, sets } => {
-                // [(Region, Input File), ..]
-                let inputs = {
-                    let mut inputs: Vec<(String, File)> = Vec::new();
-                    for path in sets {
-                        // Extract filename until the first '.'
-                        let name = path
-                            .file_name()
-                            .ok_or_else(|| anyhow!("not a file path: {}", path.to_string_lossy()))?
-                            .to_str()
-                            .ok_or_else(|| {
-                                anyhow!("bad chars in file name: {}", path.to_string_lossy())
-                            })?
-                            .chars()
-                            .take_while(|&c| c != '.')
-                            .collect::<String>();
-
-                        let file = File::open(path)?;
-                        inputs.push((name, file));
-                    }
-                    // Not necessary, but makes debugging easier
-                    // when viewing region name LuT in a hex editor.
-                    inputs.sort_by(|a, b| a.0.cmp(&b.0));
-                    inputs
-                };
+/// Builds a map storing a value cell for each cell, from `pairs`
+/// files of `<cell>,<value>` hex lines. Later pairs win conflicts.
+fn generate_cell_map(
+    pairs: &[PathBuf],
+    out: &Path,
+    compress: Compression,
+    dry_run: bool,
+) -> Result<()> {
+    let mut map: HexTreeMap<Cell> = HexTreeMap::new();
+    let mut max_res = None;
+    for path in pairs {
+        let mut count = 0_u64;
+        for (line_n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, val) = line.split_once(',').ok_or_else(|| {
+                anyhow!("{}:{}: expected <cell>,<value>", path.display(), line_n + 1)
+            })?;
+            let (_, key) = parse_cell(key.trim(), IdxBase::Hex)?;
+            let (_, val) = parse_cell(val.trim(), IdxBase::Hex)?;
+            max_res = max_res.max(Some(key.res()));
+            map.insert(key, val);
+            count += 1;
+        }
+        if dry_run {
+            println!("{}: {count} pairs", path.display());
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    let resolution = max_res.map(Resolution::try_from).transpose()?;
+    let mut output = Output::create(out, compress)?;
+    lwreg::write_cell_map(&mut output, resolution, &map)?;
+    output.finish()
+}
 
-                // Create a map of H3 cells. For values, instead of
-                // duplicating region strings, or creating an enum, we
-                // store the index into region-string LuT.
-                let mut region_map: HexTreeMap<u8> = HexTreeMap::new();
-                for (n, (_name, file)) in inputs.iter().enumerate() {
-                    let mut rdr = GzDecoder::new(file);
-                    while let Ok(entry) = rdr.read_u64::<LE>() {
-                        region_map.insert(Cell::try_from(entry)?, n as u8);
+/// Returns the JSON object [`print_value`] prints.
+fn value_json(cell_idx: u64, val: &str, matched: Option<Cell>) -> Value {
+    // GenWorld values are already serialized JSON, Generate values
+    // are bare region names.
+    let value = serde_json::from_str(val).unwrap_or_else(|_| Value::String(val.to_owned()));
+    let mut output = serde_json::json!({
+        "cell": format!("{cell_idx:x}"),
+        "value": value,
+    });
+    if let Some(matched) = matched {
+        output["matched"] = Value::String(format!("{:x}", matched.into_raw()));
+        output["matched_res"] = Value::from(matched.res());
+    }
+    output
+}
+
+/// Serves lookups of the map at `map` over HTTP on `bind`.
+///
+/// The map is memory-mapped once and shared by every worker thread.
+/// Workers look up through a shared reference, so they never wait on
+/// each other.
+#[cfg(feature = "serve")]
+fn serve(map: &Path, bind: &str) -> Result<()> {
+    let server =
+        tiny_http::Server::http(bind).map_err(|e| anyhow!("can't listen on {bind}: {e}"))?;
+    let json = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("valid header");
+    // SAFETY: the map must not be modified while it's being served,
+    // as documented on the subcommand.
+    let region_map = unsafe { RegionMap::open_mmap(map) }?;
+    log::info!("listening on {bind}");
+    std::thread::scope(|scope| {
+        for _ in 0..rayon::current_num_threads() {
+            let (server, region_map, json) = (&server, &region_map, json.clone());
+            scope.spawn(move || {
+                for request in server.incoming_requests() {
+                    let (status, body) = match lookup_request(region_map, request.url()) {
+                        Ok(output) => (200, output),
+                        Err((status, msg)) => (status, serde_json::json!({ "error": msg })),
+                    };
+                    let response = tiny_http::Response::from_string(body.to_string())
+                        .with_status_code(status)
+                        .with_header(json.clone());
+                    if let Err(e) = request.respond(response) {
+                        warn!("failed to respond: {e}");
                     }
                 }
-                // Create an array of region names that we derive from
-                // the input files base names.
-                let region_name_lut: Vec<&str> =
-                    inputs.iter().map(|(name, _)| name.as_ref()).collect();
+            });
+        }
+    });
+    Ok(())
+}
 
-                // Turn the HexTreeMap into a disktree at `out`.
-                let mut disktree_file = File::create(out)?;
-                region_map.to_disktree(&mut disktree_file, |wtr, &val| wtr.write_u8(val))?;
+/// Answers a `/lookup` request for `url`, returning the value as
+/// [`value_json`] does or an HTTP status and error message.
+#[cfg(feature = "serve")]
+fn lookup_request(
+    region_map: &RegionMap<Cursor<memmap2::Mmap>>,
+    url: &str,
+) -> std::result::Result<Value, (u16, String)> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    if path != "/lookup" {
+        return Err((404, format!("no endpoint {path}")));
+    }
+    let params: HashMap<&str, &str> = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .collect();
+    let bad_request = |e: anyhow::Error| (400, e.to_string());
+    let cell = match (params.get("cell"), params.get("lat"), params.get("lng")) {
+        (Some(idx), None, None) => parse_cell(idx, IdxBase::Hex).map_err(bad_request)?.1,
+        (None, Some(lat), Some(lng)) => (|| -> Result<Cell> {
+            let latlng = LatLng::new(lat.parse()?, lng.parse()?)?;
+            Ok(region_map.latlng_to_cell(latlng)?)
+        })()
+        .map_err(bad_request)?,
+        _ => return Err((400, "expected cell, or lat and lng".to_string())),
+    };
+    match region_map.lookup_shared(cell) {
+        Ok(Some((matched, val))) => Ok(value_json(cell.into_raw(), val, Some(matched))),
+        Ok(None) => Err((404, format!("no entry for {:x}", cell.into_raw()))),
+        Err(e) => Err((500, e.to_string())),
+    }
+}
 
-                // Append region-name LuT to end of `out` and write
-                // its position the end of the file.
-                let region_name_lut_pos = disktree_file.seek(SeekFrom::End(0))?;
-                bincode::serialize_into(&mut disktree_file, &region_name_lut)?;
-                disktree_file.write_u64::<LE>(region_name_lut_pos)?;
-            }
+/// Where a new map is written: a file, or stdout for `-`.
+///
+/// Writing a map seeks back over what's been written, which neither
+/// stdout nor a compressor can do, so those cases buffer the whole
+/// map in memory and only write it out in [`Output::finish`]. An
+/// uncompressed file is written as the map is generated and costs no
+/// extra memory.
+enum Output {
+    File(File),
+    Buffered {
+        buf: Cursor<Vec<u8>>,
+        /// `None` for stdout.
+        file: Option<File>,
+        compression: Compression,
+    },
+}
 
-            Cli::Lookup { map, idx } => {
-                let cell_idx = u64::from_str_radix(&idx, 16)?;
-                let cell = Cell::try_from(cell_idx)?;
+impl Output {
+    fn create(path: &Path, compression: Compression) -> Result<Self> {
+        let file = if path == Path::new("-") {
+            None
+        } else if compression == Compression::None {
+            return Ok(Self::File(File::create(path)?));
+        } else {
+            Some(File::create(path)?)
+        };
+        Ok(Self::Buffered {
+            buf: Cursor::new(Vec::new()),
+            file,
+            compression,
+        })
+    }
 
-                let mut disktree_file = File::open(map)?;
-                disktree_file.seek(SeekFrom::End(-(std::mem::size_of::<u64>() as i64)))?;
-                let region_name_lut_pos = disktree_file.read_u64::<LE>()?;
-                disktree_file.seek(SeekFrom::Start(region_name_lut_pos))?;
-                let region_name_lut: Vec<String> = bincode::deserialize_from(&mut disktree_file)?;
+    /// Compresses a buffered map and writes it out.
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::File(_) => (),
+            Self::Buffered {
+                buf,
+                file: Some(file),
+                compression,
+            } => compression.compress(file, buf.get_ref())?,
+            Self::Buffered {
+                buf,
+                file: None,
+                compression,
+            } => {
+                let mut stdout = io::stdout().lock();
+                compression.compress(&mut stdout, buf.get_ref())?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
 
-                let mut disktree = DiskTree::from_reader(disktree_file)?;
+/// Fails if writing to `out`, or with `split` to the files
+/// [`RegionOutput`] names after it, would replace an existing file,
+/// unless `force` is set. Generating can take hours, so a reused
+/// path shouldn't silently destroy an earlier map.
+fn check_clobber(out: &Path, split: bool, force: bool) -> Result<()> {
+    if force || out == Path::new("-") {
+        return Ok(());
+    }
+    let paths = if split {
+        vec![out.with_extension("disktree"), out.with_extension("lut")]
+    } else {
+        vec![out.to_owned()]
+    };
+    match paths.iter().find(|path| path.exists()) {
+        Some(path) => Err(anyhow!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        )),
+        None => Ok(()),
+    }
+}
 
-                let (_, rdr) = disktree
-                    .seek_to_cell(cell)?
-                    .ok_or_else(|| anyhow::anyhow!("no entry"))?;
-                let region_name_lut_idx = rdr.read_u8()?;
+/// Where generate and gen-world write a region map: an [`Output`], or
+/// with `--split` the files [`write_split_region_map`] writes the
+/// disktree and the LuT to.
+enum RegionOutput {
+    Combined(Output),
+    Split { tree: File, lut: File },
+}
 
-                let val = region_name_lut
-                    .get(region_name_lut_idx as usize)
-                    .ok_or_else(|| {
-                        anyhow::anyhow!("no interned value for index {region_name_lut_idx}")
-                    })?;
+impl RegionOutput {
+    /// Creates the output for `path`, or with `split` the files named
+    /// after it with the extensions .disktree and .lut.
+    fn create(path: &Path, compression: Compression, split: bool) -> Result<Self> {
+        if !split {
+            return Ok(Self::Combined(Output::create(path, compression)?));
+        }
+        if path == Path::new("-") {
+            return Err(anyhow!("--split can't write to stdout"));
+        }
+        Ok(Self::Split {
+            tree: File::create(path.with_extension("disktree"))?,
+            lut: File::create(path.with_extension("lut"))?,
+        })
+    }
 
-                println!("{val}");
+    fn write_region_map(
+        &mut self,
+        resolution: Option<Resolution>,
+        map: &HexTreeMap<u16>,
+        lut: &[impl serde::Serialize],
+        gzip_lut: bool,
+        value_width: Option<ValueWidth>,
+    ) -> Result<()> {
+        match self {
+            Self::Combined(output) => {
+                write_region_map(output, resolution, map, lut, gzip_lut, value_width)?
+            }
+            Self::Split {
+                tree,
+                lut: lut_file,
+            } => {
+                write_split_region_map(tree, lut_file, resolution, map, lut, gzip_lut, value_width)?
             }
         }
         Ok(())
     }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Combined(output) => output.finish(),
+            Self::Split { .. } => Ok(()),
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    cli.run()
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Buffered { buf: cursor, .. } => cursor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Buffered { buf, .. } => buf.flush(),
+        }
+    }
+}
+
+impl Seek for Output {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            Self::Buffered { buf, .. } => buf.seek(pos),
+        }
+    }
+}
+
+/// Reads a `--names` manifest, returning region names keyed by
+/// canonical path.
+fn read_names(manifest: &Path) -> Result<HashMap<PathBuf, String>> {
+    let dir = manifest.parent().unwrap_or(Path::new("."));
+    let entries: HashMap<PathBuf, String> = toml::from_str(&std::fs::read_to_string(manifest)?)?;
+    Ok(entries
+        .into_iter()
+        .map(|(path, name)| {
+            let path = dir.join(path);
+            // Sets that don't exist can't be inputs anyway, so there's
+            // no need to fail on them here.
+            (path.canonicalize().unwrap_or(path), name)
+        })
+        .collect())
+}
+
+/// Opens each input set, returning `(region, file, format)` sorted by
+/// region name and then canonical path.
+///
+/// Output LuT indices follow this order, so it's part of what makes
+/// maps reproducible: names compare as bytes, so the locale has no
+/// say, and the path breaks ties between sets with the same name.
+///
+/// A set's region is its entry in `names`, or else its file name up
+/// to the first '.'. Its format is `format` or else inferred from its
+/// extension. A set given as `-` is read from stdin and named
+/// `stdin_name`. Sets sharing a name are an error listing them, or
+/// just a warning with `allow_dup_names`.
+fn open_sets(
+    sets: Vec<PathBuf>,
+    format: Option<SetFormat>,
+    names: &HashMap<PathBuf, String>,
+    stdin_name: Option<&str>,
+    allow_dup_names: bool,
+) -> Result<Vec<(String, SetSource, SetFormat)>> {
+    let mut inputs: Vec<(String, PathBuf, SetSource, SetFormat)> = Vec::new();
+    let mut read_stdin = false;
+    for path in sets {
+        if path == Path::new("-") {
+            if read_stdin {
+                return Err(anyhow!("stdin can only be given as a set once"));
+            }
+            read_stdin = true;
+            let name =
+                stdin_name.ok_or_else(|| anyhow!("a set read from stdin needs --stdin-name"))?;
+            let mut buf = Vec::new();
+            io::stdin().lock().read_to_end(&mut buf)?;
+            let format = format.unwrap_or(SetFormat::H3idz);
+            inputs.push((name.to_owned(), path, SetSource::Stdin(buf), format));
+            continue;
+        }
+        let canonical = path.canonicalize()?;
+        let name = match names.get(&canonical) {
+            Some(name) => name.clone(),
+            // Extract filename until the first '.'
+            None => path
+                .file_name()
+                .ok_or_else(|| anyhow!("not a file path: {}", path.to_string_lossy()))?
+                .to_str()
+                .ok_or_else(|| anyhow!("bad chars in file name: {}", path.to_string_lossy()))?
+                .chars()
+                .take_while(|&c| c != '.')
+                .collect::<String>(),
+        };
+
+        let format = format.unwrap_or_else(|| SetFormat::from_path(&path));
+        let file = File::open(path)?;
+        inputs.push((name, canonical, SetSource::File(file), format));
+    }
+    inputs.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    let mut dups = Vec::new();
+    for group in inputs.chunk_by(|a, b| a.0 == b.0) {
+        if group.len() > 1 {
+            let paths: Vec<String> = group
+                .iter()
+                .map(|input| input.1.display().to_string())
+                .collect();
+            let dup = format!("{} are all named {}", paths.join(", "), group[0].0);
+            if allow_dup_names {
+                warn!("{dup}");
+            } else {
+                dups.push(dup);
+            }
+        }
+    }
+    if !dups.is_empty() {
+        return Err(anyhow!(
+            "{}; name them apart with --names or pass --allow-dup-names",
+            dups.join("; ")
+        ));
+    }
+    Ok(inputs
+        .into_iter()
+        .map(|(name, _, set, format)| (name, set, format))
+        .collect())
+}
+
+/// Decodes the cells of each of `inputs`, in order, truncating them
+/// to `resolution` if given.
+fn decode_sets(
+    inputs: &[(String, SetSource, SetFormat)],
+    resolution: Option<Resolution>,
+    compact: bool,
+) -> Result<Vec<Vec<CellIndex>>> {
+    let _span = info_span!("decode").entered();
+    // Decompressing is the slow part, so decode every set in
+    // parallel. The results are collected in input order.
+    inputs
+        .par_iter()
+        .map(|(name, set, format)| {
+            let _span = info_span!("decode_set", name = name.as_str()).entered();
+            let cells = format.read(set)?;
+            let Some(res) = resolution else {
+                return Ok(cells);
+            };
+            if cells.iter().any(|cell| cell.resolution() < res) {
+                warn!("{name} has cells coarser than res {res}, which can't be refined");
+            }
+            Ok(coarsen_cells(cells, res, compact)?)
+        })
+        .collect()
+}
+
+/// Returns the resolution every cell of `decoded` shares, for
+/// `--resolution auto`, or `None` if there are no cells.
+///
+/// Mixed resolutions are an error unless `mixed_res`, when the finest
+/// is returned so that no cell is stored finer than the map says.
+fn detect_resolution(
+    inputs: &[(String, SetSource, SetFormat)],
+    decoded: &[Vec<CellIndex>],
+    mixed_res: bool,
+) -> Result<Option<Resolution>> {
+    let mut first: Option<(&str, Resolution)> = None;
+    let mut finest = None;
+    // Only the first mismatch is warned about.
+    let mut mixed = false;
+    for ((name, _, _), cells) in inputs.iter().zip(decoded) {
+        for cell in cells {
+            let res = cell.resolution();
+            let (first_name, first_res) = *first.get_or_insert((name.as_str(), res));
+            if res != first_res && !mixed {
+                let msg =
+                    format!("{name} has res {res} cells, but {first_name} has res {first_res}");
+                if !mixed_res {
+                    return Err(anyhow!("{msg}; pass --mixed-res to allow it"));
+                }
+                warn!("{msg}");
+                mixed = true;
+            }
+            finest = finest.max(Some(res));
+        }
+    }
+    Ok(finest)
+}
+
+/// Checks that `--max-compact-res` isn't finer than the map's
+/// resolution, which its cells would then be stored finer than.
+fn check_compact_res(min_res: Resolution, resolution: Option<Resolution>) -> Result<()> {
+    match resolution {
+        Some(res) if min_res > res => Err(anyhow!(
+            "--max-compact-res {min_res} is finer than the map's res {res}"
+        )),
+        None => Err(anyhow!("--max-compact-res needs a map resolution")),
+        _ => Ok(()),
+    }
+}
+
+/// Prints to stderr how many cells at `res` each region in `map`
+/// covers and how many it's stored as, in LuT order.
+fn print_compaction_stats<S: AsRef<str>>(
+    map: &HexTreeMap<u16>,
+    lut: &[S],
+    res: Resolution,
+) -> Result<()> {
+    // LuT index -> (cells at `res`, stored cells)
+    let mut counts = vec![(0_u64, 0_u64); lut.len()];
+    for (cell, &lut_idx) in map.iter() {
+        let count = &mut counts[usize::from(lut_idx)];
+        count.0 += CellIndex::try_from(cell.into_raw())?.children_count(res);
+        count.1 += 1;
+    }
+    for (region, (expanded, stored)) in lut.iter().zip(counts) {
+        eprintln!("region {}: {expanded} -> {stored} cells", region.as_ref());
+    }
+    Ok(())
+}
+
+/// Warns about each region in `map` that covers fewer than seven
+/// cells at `res`, or whose cells split into more than
+/// `max_components` groups of adjacent cells. `name` names a region
+/// by its value.
+///
+/// A stored cell is adjacent to whatever cell of the same region
+/// covers one of its neighbours at its own resolution, which links a
+/// compacted cell to finer ones along its edge from their side. H3
+/// children don't exactly tile their parent, so this is approximate,
+/// but good enough to spot fragmented regions.
+fn warn_fragments_of<V: Copy + Ord>(
+    map: &HexTreeMap<V>,
+    res: Resolution,
+    max_components: usize,
+    name: impl Fn(V) -> String,
+) -> Result<()> {
+    fn root(parents: &mut [usize], mut idx: usize) -> usize {
+        while parents[idx] != idx {
+            parents[idx] = parents[parents[idx]];
+            idx = parents[idx];
+        }
+        idx
+    }
+
+    let cells: Vec<(Cell, V)> = map.iter().map(|(cell, &val)| (cell, val)).collect();
+    let positions: HashMap<u64, usize> = cells
+        .iter()
+        .enumerate()
+        .map(|(idx, (cell, _))| (cell.into_raw(), idx))
+        .collect();
+    // Union-find over stored cells.
+    let mut parents: Vec<usize> = (0..cells.len()).collect();
+    // Region -> (cells at `res`, components)
+    let mut regions: BTreeMap<V, (u64, usize)> = BTreeMap::new();
+    for (idx, &(cell, val)) in cells.iter().enumerate() {
+        let cell = CellIndex::try_from(cell.into_raw())?;
+        regions.entry(val).or_default().0 += cell.children_count(res);
+        for neighbor in cell.grid_disk::<Vec<_>>(1) {
+            match map.get(Cell::from_raw(neighbor.into())?) {
+                Some((matched, &neighbor_val)) if neighbor_val == val => {
+                    let a = root(&mut parents, idx);
+                    let b = root(&mut parents, positions[&matched.into_raw()]);
+                    parents[a] = b;
+                }
+                _ => (),
+            }
+        }
+    }
+    for (idx, &(_, val)) in cells.iter().enumerate() {
+        if root(&mut parents, idx) == idx {
+            regions.entry(val).or_default().1 += 1;
+        }
+    }
+    for (val, (count, components)) in regions {
+        if components > max_components {
+            warn!("{} is split into {components} fragments", name(val));
+        } else if count < 7 {
+            warn!("{} covers only {count} cells at res {res}", name(val));
+        }
+    }
+    Ok(())
+}
+
+/// How many of the most overlapping pairs of features
+/// [`warn_overlaps`] names.
+const TOP_OVERLAPS: usize = 5;
+
+/// Returns whichever of `earlier` and `later` claims on a cell
+/// `conflict` keeps, to say so when reporting overlaps.
+fn conflict_winner<'a>(conflict: ConflictPolicy, earlier: &'a str, later: &'a str) -> &'a str {
+    match conflict {
+        ConflictPolicy::First => earlier,
+        ConflictPolicy::Last | ConflictPolicy::Warn | ConflictPolicy::Error => later,
+    }
+}
+
+/// Warns how many cells more than one feature claimed, which of them
+/// `conflict` kept, and which pairs of values, described by `name`,
+/// share the most.
+fn warn_overlaps<V: Copy>(
+    overlaps: &BTreeMap<(V, V), u64>,
+    conflict: ConflictPolicy,
+    name: impl Fn(V) -> String,
+) {
+    let total: u64 = overlaps.values().sum();
+    if total == 0 {
+        return;
+    }
+    warn!(
+        "{total} cells are claimed by more than one feature, {} won",
+        conflict_winner(conflict, "earlier features", "later features")
+    );
+    let mut pairs: Vec<(&(V, V), &u64)> = overlaps.iter().collect();
+    // Stable, so ties stay in value order.
+    pairs.sort_by(|a, b| b.1.cmp(a.1));
+    for (&(earlier, later), count) in pairs.into_iter().take(TOP_OVERLAPS) {
+        warn!(
+            "  {} overlaps {} in {count} cells",
+            name(later),
+            name(earlier)
+        );
+    }
+}
+
+/// Looks up each of `cells` and prints the regions found, with the
+/// number of cells in each, most cells first.
+fn print_region_counts(
+    region_map: &mut RegionMap,
+    cells: impl IntoIterator<Item = CellIndex>,
+) -> Result<()> {
+    let mut counts: BTreeMap<u16, usize> = BTreeMap::new();
+    for cell in cells {
+        if let Some((_, lut_idx)) = region_map.lookup_index(Cell::from_raw(cell.into())?)? {
+            *counts.entry(lut_idx).or_default() += 1;
+        }
+    }
+    let mut counts: Vec<(u16, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1));
+    for (lut_idx, count) in counts {
+        println!("{}: {count}", region_map.resolve(lut_idx)?);
+    }
+    Ok(())
+}
+
+/// Returns the largest cells within `cell` that `map` has nothing
+/// stored at or under, where neither `cell` nor any of its parents
+/// is stored.
+fn uncovered_within<V>(map: &HexTreeMap<V>, cell: Cell) -> Result<Vec<Cell>> {
+    if map.subtree_iter(cell).next().is_none() {
+        return Ok(vec![cell]);
+    }
+    let cell = CellIndex::try_from(cell.into_raw())?;
+    // Something is stored under `cell`, so it can't be at res 15.
+    let res = cell.resolution().succ().expect("cell has children");
+    let mut uncovered = Vec::new();
+    for child in cell.children(res) {
+        let child = Cell::from_raw(child.into())?;
+        if map.get(child).is_none() {
+            uncovered.extend(uncovered_within(map, child)?);
+        }
+    }
+    Ok(uncovered)
+}
+
+fn to_feature(geometry: geojson::Value, properties: JsonObject) -> Feature {
+    Feature {
+        bbox: None,
+        geometry: Some(geojson::Geometry::new(geometry)),
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    }
+}
+
+impl Cmd {
+    fn run(self, dry_run: bool) -> Result<()> {
+        match self {
+            Cmd::GenWorld {
+                resolution,
+                containment,
+                property,
+                id_property,
+                no_compact,
+                max_compact_res,
+                keep_going,
+                conflict,
+                value_type,
+                stats,
+                warn_fragments,
+                gzip_lut,
+                compress,
+                split,
+                value_width,
+                force,
+                format,
+                out,
+                world,
+            } => {
+                let conflict = ConflictPolicy::from(conflict);
+                let compress = Compression::from(compress);
+                let value_width = value_width.map(ValueWidth::from);
+                match (value_type, &property) {
+                    (ValueType::Cell, _) => return Err(anyhow!("gen-world can't store cells")),
+                    (ValueType::Index, Some(_)) => {
+                        return Err(anyhow!("--property doesn't apply to index values"))
+                    }
+                    (ValueType::Index, _) if split => {
+                        return Err(anyhow!(
+                            "--split needs a LuT, which index values don't have"
+                        ))
+                    }
+                    _ => (),
+                }
+                if !dry_run {
+                    check_clobber(&out, split, force)?;
+                }
+                // Draws to stderr, and only when it's a terminal.
+                let progress = if dry_run {
+                    ProgressBar::hidden()
+                } else {
+                    ProgressBar::no_length()
+                };
+                let features = read_world(&world, format, Some(&progress))?;
+
+                if let Some(min_res) = max_compact_res {
+                    check_compact_res(min_res, Some(resolution))?;
+                }
+                if dry_run {
+                    let mut feature_count = 0;
+                    for feature in features {
+                        feature?;
+                        feature_count += 1;
+                    }
+                    println!("{feature_count} features at res {resolution}");
+                    return Ok(());
+                }
+                let mut output = RegionOutput::create(&out, compress, split)?;
+
+                // A topology is read whole, so its feature count is
+                // known up front. A streamed GeoJSON document's isn't,
+                // so its bar counts bytes read instead.
+                let progress = match features.size_hint() {
+                    (lower, Some(upper)) if lower == upper => {
+                        progress.set_length(lower as u64);
+                        progress.with_style(ProgressStyle::with_template(
+                            "{wide_bar} {pos}/{len} features, eta {eta}",
+                        )?)
+                    }
+                    _ => progress.with_style(ProgressStyle::with_template(
+                        "{wide_bar} {bytes}/{total_bytes}, eta {eta}",
+                    )?),
+                };
+                let on_progress = |step: Progress| {
+                    if step.phase == Phase::Insert && step.total.is_some() {
+                        progress.set_position(step.done as u64);
+                    }
+                };
+                let opts = WorldOptions {
+                    containment: containment.into(),
+                    property: property.as_deref(),
+                    id_property: id_property.as_deref(),
+                    compact: !no_compact,
+                    keep_going,
+                    conflict,
+                    ..WorldOptions::new(resolution)
+                };
+                let (empty, failed, feature_count) = if value_type == ValueType::Index {
+                    let mut world = gen_world_indices(features, opts, Some(&on_progress))?;
+                    if let Some(min_res) = max_compact_res {
+                        world.map = cap_compaction(&world.map, min_res)?;
+                    }
+                    progress.finish_and_clear();
+                    warn_overlaps(&world.overlaps, conflict, |idx| format!("feature {idx}"));
+                    if let Some(max_components) = warn_fragments {
+                        warn_fragments_of(&world.map, resolution, max_components, |idx| {
+                            format!("feature {idx}")
+                        })?;
+                    }
+                    let RegionOutput::Combined(output) = &mut output else {
+                        unreachable!("--split is rejected for index values");
+                    };
+                    write_map(output, Some(resolution), &world.map, |wtr, &idx| {
+                        wtr.write_u32::<LE>(idx)
+                    })?;
+                    (world.empty, world.failed, world.feature_count)
+                } else {
+                    let mut world = gen_world(features, opts, Some(&on_progress))?;
+                    progress.finish_and_clear();
+                    warn_overlaps(&world.overlaps, conflict, |idx| {
+                        world.lut[usize::from(idx)].clone()
+                    });
+                    if let Some(min_res) = max_compact_res {
+                        world.map = cap_compaction(&world.map, min_res)?;
+                    }
+                    if stats {
+                        print_compaction_stats(&world.map, &world.lut, resolution)?;
+                    }
+                    if let Some(max_components) = warn_fragments {
+                        warn_fragments_of(&world.map, resolution, max_components, |idx| {
+                            world.lut[usize::from(idx)].clone()
+                        })?;
+                    }
+                    output.write_region_map(
+                        Some(resolution),
+                        &world.map,
+                        &world.lut,
+                        gzip_lut,
+                        value_width,
+                    )?;
+                    (world.empty, world.failed, world.feature_count)
+                };
+                output.finish()?;
+
+                if !empty.is_empty() {
+                    warn!(
+                        "{} features have no cells at res {resolution} and were skipped:",
+                        empty.len()
+                    );
+                    for (idx, value) in &empty {
+                        warn!("  feature {idx}: {value}");
+                    }
+                }
+
+                if !failed.is_empty() {
+                    warn!("{} features failed and were skipped:", failed.len());
+                    for e in &failed {
+                        warn!("  {e}");
+                    }
+                    return Err(anyhow!(
+                        "{} of {feature_count} features failed",
+                        failed.len()
+                    ));
+                }
+            }
+
+            Cmd::Generate {
+                resolution,
+                no_compact,
+                max_compact_res,
+                conflict,
+                format,
+                names,
+                stdin_name,
+                allow_dup_names,
+                mixed_res,
+                value_type,
+                stats,
+                gzip_lut,
+                compress,
+                split,
+                value_width,
+                force,
+                out,
+                sets,
+            } => {
+                let conflict = ConflictPolicy::from(conflict);
+                let compress = Compression::from(compress);
+                let value_width = value_width.map(ValueWidth::from);
+                if !dry_run {
+                    check_clobber(&out, split, force)?;
+                }
+                match value_type {
+                    ValueType::Region => (),
+                    ValueType::Cell | ValueType::Index if split => {
+                        return Err(anyhow!("--split needs a LuT, which raw values don't have"))
+                    }
+                    ValueType::Cell => return generate_cell_map(&sets, &out, compress, dry_run),
+                    ValueType::Index if gzip_lut => {
+                        return Err(anyhow!("--gzip-lut doesn't apply to index values"))
+                    }
+                    ValueType::Index => (),
+                }
+                let auto_res = resolution == Some(ResolutionArg::Auto);
+                let resolution = match resolution {
+                    Some(ResolutionArg::Res(res)) => Some(res),
+                    Some(ResolutionArg::Auto) | None => None,
+                };
+                let names = match names {
+                    Some(manifest) => read_names(&manifest)?,
+                    None => HashMap::new(),
+                };
+                let inputs =
+                    open_sets(sets, format, &names, stdin_name.as_deref(), allow_dup_names)?;
+                // Each set gets its own u16 LuT index.
+                let max_sets = ValueWidth::U16.max_len();
+                if inputs.len() > max_sets {
+                    return Err(anyhow!(
+                        "too many input sets: at most {max_sets} supported, found {}",
+                        inputs.len()
+                    ));
+                }
+                let decoded = decode_sets(&inputs, resolution, !no_compact)?;
+                let resolution = if auto_res {
+                    let detected = detect_resolution(&inputs, &decoded, mixed_res)?;
+                    if let Some(res) = detected {
+                        log::info!("input cells are res {res}");
+                    }
+                    detected
+                } else {
+                    resolution
+                };
+                if dry_run {
+                    for ((name, _, _), cells) in inputs.iter().zip(&decoded) {
+                        println!("{name}: {} cells", cells.len());
+                    }
+                    if let (true, Some(res)) = (auto_res, resolution) {
+                        println!("resolution: {res}");
+                    }
+                    return Ok(());
+                }
+
+                // Create a map of H3 cells. For values, instead of
+                // duplicating region strings, or creating an enum, we
+                // store the index into region-string LuT.
+                //
+                // Insertion stays serial and in input order, so
+                // overlapping cells resolve the same way regardless
+                // of thread count.
+                let mut region_map: HexTreeMap<u16> = HexTreeMap::new();
+                // Finest resolution of any input cell, recorded in
+                // the header as the map's resolution unless one was
+                // requested.
+                let mut max_res = None;
+                // Resolution of the first input cell, which every
+                // other one must match unless truncated. Only the
+                // first mismatch is warned about.
+                let mut first_res = None;
+                let mut check_res = resolution.is_none();
+                let mut conflicts = 0_u64;
+                let insert_span = info_span!("insert").entered();
+                for (n, cells) in decoded.into_iter().enumerate() {
+                    for (cell_n, cell) in cells.into_iter().enumerate() {
+                        if check_res {
+                            let first = *first_res.get_or_insert(cell.resolution());
+                            if cell.resolution() != first {
+                                let msg = format!(
+                                    "cell {cell_n} of {} is res {}, but the first input cell is res {first}",
+                                    inputs[n].0,
+                                    cell.resolution()
+                                );
+                                if !mixed_res {
+                                    return Err(anyhow!("{msg}"));
+                                }
+                                warn!("{msg}");
+                                check_res = false;
+                            }
+                        }
+                        max_res = max_res.max(Some(cell.resolution()));
+                        let claims = conflict.insert(
+                            &mut region_map,
+                            Cell::from_raw(cell.into())?,
+                            n as u16,
+                        );
+                        if let (ConflictPolicy::Error, Some(&(shared, existing))) =
+                            (conflict, claims.first())
+                        {
+                            return Err(anyhow!(
+                                "cell {:x} is in both {} and {}",
+                                shared.into_raw(),
+                                inputs[usize::from(existing)].0,
+                                inputs[n].0
+                            ));
+                        }
+                        conflicts += claims.len() as u64;
+                    }
+                }
+                drop(insert_span);
+                if conflicts > 0 {
+                    warn!(
+                        "{conflicts} cells are in more than one set, {} won",
+                        conflict_winner(conflict, "earlier sets", "later sets")
+                    );
+                }
+                let resolution = resolution.or(max_res);
+                if let Some(min_res) = max_compact_res {
+                    check_compact_res(min_res, resolution)?;
+                    region_map = cap_compaction(&region_map, min_res)?;
+                }
+                // Create an array of region names that we derive from
+                // the input files base names.
+                let region_name_lut: Vec<&str> =
+                    inputs.iter().map(|(name, _, _)| name.as_ref()).collect();
+
+                if let (true, Some(res)) = (stats, resolution) {
+                    print_compaction_stats(&region_map, &region_name_lut, res)?;
+                }
+
+                if value_type == ValueType::Index {
+                    for (idx, name) in region_name_lut.iter().enumerate() {
+                        eprintln!("{idx}: {name}");
+                    }
+                    // Stored as u32 like gen-world's, so that lookup
+                    // --value-type index reads either.
+                    let mut output = Output::create(&out, compress)?;
+                    write_map(&mut output, resolution, &region_map, |wtr, &idx| {
+                        wtr.write_u32::<LE>(u32::from(idx))
+                    })?;
+                    return output.finish();
+                }
+
+                // Turn the HexTreeMap into a disktree at `out`.
+                let mut output = RegionOutput::create(&out, compress, split)?;
+                output.write_region_map(
+                    resolution,
+                    &region_map,
+                    &region_name_lut,
+                    gzip_lut,
+                    value_width,
+                )?;
+                output.finish()?;
+            }
+
+            Cmd::Append {
+                conflict,
+                no_compact,
+                format,
+                stdin_name,
+                map,
+                sets,
+            } => {
+                let conflict = ConflictPolicy::from(conflict);
+                let mut region_map = RegionMap::open(&map)?;
+                let mut lut = region_map.read_lut()?;
+                let mut merged: HexTreeMap<u16> = HexTreeMap::new();
+                for entry in region_map.iter_indices()? {
+                    let (cell, idx) = entry?;
+                    merged.insert(cell, idx);
+                }
+                // The stored resolution is what lookups coerce to, so
+                // new cells finer than it would never be found.
+                let resolution = region_map.resolution();
+                let gzip_lut = region_map.header().gzip_lut;
+                let value_width = region_map.header().value_width;
+                let compression = region_map.compression();
+                let split = region_map.header().split_lut;
+                drop(region_map);
+
+                // Same-named sets just add to the same region here.
+                let inputs = open_sets(sets, format, &HashMap::new(), stdin_name.as_deref(), true)?;
+                let decoded = decode_sets(&inputs, resolution, !no_compact)?;
+                let mut max_res = resolution;
+                let mut conflicts = 0_u64;
+                for ((name, _, _), cells) in inputs.iter().zip(decoded) {
+                    // A set named like an existing region adds to it.
+                    let idx = match lut.iter().position(|val| val == name) {
+                        Some(idx) => idx as u16,
+                        None => {
+                            let idx = u16::try_from(lut.len()).map_err(|_| {
+                                lwreg::Error::TooManyValues {
+                                    max: ValueWidth::U16.max_len(),
+                                    found: lut.len() + 1,
+                                }
+                            })?;
+                            lut.push(name.clone());
+                            idx
+                        }
+                    };
+                    for cell in cells {
+                        max_res = max_res.max(Some(cell.resolution()));
+                        let claims =
+                            conflict.insert(&mut merged, Cell::from_raw(cell.into())?, idx);
+                        if let (ConflictPolicy::Error, Some(&(shared, existing))) =
+                            (conflict, claims.first())
+                        {
+                            return Err(anyhow!(
+                                "cell {:x} is in both {} and {name}",
+                                shared.into_raw(),
+                                lut[usize::from(existing)]
+                            ));
+                        }
+                        conflicts += claims.len() as u64;
+                    }
+                }
+                if conflicts > 0 {
+                    warn!(
+                        "{conflicts} cells were already claimed, {} won",
+                        conflict_winner(conflict, "existing cells", "new sets")
+                    );
+                }
+
+                // Write alongside and rename over the original, so
+                // readers never see a partial map.
+                let tmp_path = |path: &Path| {
+                    let mut tmp_path = path.to_owned().into_os_string();
+                    tmp_path.push(".tmp");
+                    PathBuf::from(tmp_path)
+                };
+                // Keep the width, unless the new regions outgrow it.
+                let value_width = value_width.max(ValueWidth::for_len(lut.len()));
+                if split {
+                    let lut_path = map.with_extension("lut");
+                    let (tree_tmp, lut_tmp) = (tmp_path(&map), tmp_path(&lut_path));
+                    write_split_region_map(
+                        File::create(&tree_tmp)?,
+                        File::create(&lut_tmp)?,
+                        max_res,
+                        &merged,
+                        &lut,
+                        gzip_lut,
+                        Some(value_width),
+                    )?;
+                    // The new LuT only adds entries, so the old
+                    // disktree still reads with it in the meantime.
+                    std::fs::rename(&lut_tmp, &lut_path)?;
+                    std::fs::rename(&tree_tmp, &map)?;
+                    return Ok(());
+                }
+                let tree_tmp = tmp_path(&map);
+                let mut output = Output::create(&tree_tmp, compression)?;
+                write_region_map(
+                    &mut output,
+                    max_res,
+                    &merged,
+                    &lut,
+                    gzip_lut,
+                    Some(value_width),
+                )?;
+                output.finish()?;
+                std::fs::rename(&tree_tmp, &map)?;
+            }
+
+            Cmd::Merge {
+                conflict,
+                gzip_lut,
+                out,
+                maps,
+            } => {
+                let conflict = ConflictPolicy::from(conflict);
+                let mut merged: HexTreeMap<u16> = HexTreeMap::new();
+                let mut lut: Vec<String> = Vec::new();
+                let mut lut_indices: HashMap<String, u16> = HashMap::new();
+                let mut resolution = None;
+                let mut conflicts = 0_u64;
+                for path in &maps {
+                    let mut region_map = RegionMap::open(path)?;
+                    resolution = resolution.max(region_map.resolution());
+
+                    // This map's LuT index -> merged LuT index, with
+                    // identical values sharing one entry.
+                    let remap = region_map
+                        .read_lut()?
+                        .into_iter()
+                        .map(|val| match lut_indices.get(&val) {
+                            Some(&idx) => Ok(idx),
+                            None => {
+                                let idx = u16::try_from(lut.len()).map_err(|_| {
+                                    anyhow!("merged LuT exceeds {} entries", lut.len())
+                                })?;
+                                lut.push(val.clone());
+                                lut_indices.insert(val, idx);
+                                Ok(idx)
+                            }
+                        })
+                        .collect::<Result<Vec<u16>>>()?;
+
+                    for entry in region_map.iter_indices()? {
+                        let (cell, idx) = entry?;
+                        // `iter_indices` has checked `idx` against the
+                        // LuT, which `remap` covers.
+                        let idx = remap[usize::from(idx)];
+                        let claims = conflict.insert(&mut merged, cell, idx);
+                        if let (ConflictPolicy::Error, Some(&(shared, existing))) =
+                            (conflict, claims.first())
+                        {
+                            return Err(anyhow!(
+                                "cell {:x} is {} in an earlier map and {} in {}",
+                                shared.into_raw(),
+                                lut[usize::from(existing)],
+                                lut[usize::from(idx)],
+                                path.display()
+                            ));
+                        }
+                        conflicts += claims.len() as u64;
+                    }
+                }
+                if conflicts > 0 {
+                    warn!(
+                        "{conflicts} cells are in more than one map, {} won",
+                        conflict_winner(conflict, "earlier maps", "later maps")
+                    );
+                }
+
+                let mut output = Output::create(&out, Compression::None)?;
+                write_region_map(&mut output, resolution, &merged, &lut, gzip_lut, None)?;
+                output.finish()?;
+            }
+
+            Cmd::Subset { map, out, regions } => {
+                let mut region_map = RegionMap::open(&map)?;
+                let source_lut = region_map.read_lut()?;
+                for region in &regions {
+                    if !source_lut.contains(region) {
+                        return Err(anyhow!("{} has no region {region}", map.display()));
+                    }
+                }
+                // Source LuT index -> subset LuT index, for kept
+                // regions.
+                let mut lut: Vec<&str> = Vec::new();
+                let remap: Vec<Option<u16>> = source_lut
+                    .iter()
+                    .map(|val| {
+                        regions.contains(val).then(|| {
+                            lut.push(val);
+                            (lut.len() - 1) as u16
+                        })
+                    })
+                    .collect();
+
+                let mut subset: HexTreeMap<u16> = HexTreeMap::new();
+                for entry in region_map.iter_indices()? {
+                    let (cell, idx) = entry?;
+                    if let Some(idx) = remap[usize::from(idx)] {
+                        subset.insert(cell, idx);
+                    }
+                }
+
+                let mut output = Output::create(&out, region_map.compression())?;
+                write_region_map(
+                    &mut output,
+                    region_map.resolution(),
+                    &subset,
+                    &lut,
+                    region_map.header().gzip_lut,
+                    Some(region_map.header().value_width),
+                )?;
+                output.finish()?;
+            }
+
+            Cmd::Lookup {
+                map,
+                idxs,
+                json,
+                format,
+                show_cell,
+                base,
+                value_type,
+                raw,
+                at_res,
+            } => {
+                let idxs = if idxs.is_empty() {
+                    std::io::stdin()
+                        .lines()
+                        .collect::<Result<Vec<String>, _>>()?
+                } else {
+                    idxs
+                };
+
+                let mut region_map = RegionMap::open(map)?;
+                warn_on_version_mismatch(region_map.header());
+                if let (Some(res), Some(map_res)) = (at_res, region_map.resolution()) {
+                    if res < map_res {
+                        warn!(
+                            "--at-res {res} is coarser than the map's res {map_res}, \
+                             so only cells compacted to res {res} or coarser will match"
+                        );
+                    }
+                }
+
+                // Misses are reported as they happen so that a single
+                // unmapped cell doesn't abort the whole batch.
+                let mut misses = 0;
+                for idx in idxs.iter().map(|idx| idx.trim()) {
+                    let (cell_idx, cell) = parse_cell(idx, base)?;
+                    let cell = match at_res {
+                        Some(res) => cell_at_res(cell, res)?,
+                        None => cell,
+                    };
+
+                    let found = match (value_type, raw) {
+                        (ValueType::Region, false) => region_map.lookup(cell)?,
+                        (ValueType::Region, true) => region_map
+                            .lookup_index(cell)?
+                            .map(|(matched, idx)| (matched, idx.to_string())),
+                        (ValueType::Cell, false) => region_map
+                            .lookup_cell(cell)?
+                            .map(|(matched, val)| (matched, format!("{:x}", val.into_raw()))),
+                        _ if region_map.header().has_lut => return Err(lwreg::Error::HasLut.into()),
+                        (ValueType::Cell, true) => region_map
+                            .read_value(cell, |rdr| rdr.read_u64::<LE>())?
+                            .map(|(matched, raw)| (matched, raw.to_string())),
+                        (ValueType::Index, _) => region_map
+                            .read_value(cell, |rdr| rdr.read_u32::<LE>())?
+                            .map(|(matched, idx)| (matched, idx.to_string())),
+                    };
+                    let Some((matched, val)) = found else {
+                        if format == LookupFormat::Jsonl {
+                            let miss = serde_json::json!({
+                                "cell": format!("{cell_idx:x}"),
+                                "value": null,
+                            });
+                            println!("{miss}");
+                        } else {
+                            warn!("no entry for {idx}");
+                            misses += 1;
+                        }
+                        continue;
+                    };
+
+                    let json = json || format != LookupFormat::Text;
+                    print_value(cell_idx, &val, show_cell.then_some(matched), json);
+                }
+
+                if misses > 0 {
+                    return Err(
+                        Miss(format!("no entry for {misses} of {} indices", idxs.len())).into(),
+                    );
+                }
+            }
+
+            Cmd::LookupMulti { maps, idx, base } => {
+                let (_, cell) = parse_cell(idx.trim(), base)?;
+                let mut misses = 0;
+                for path in &maps {
+                    let label = path.file_name().map_or_else(
+                        || path.display().to_string(),
+                        |name| name.to_string_lossy().into_owned(),
+                    );
+                    match RegionMap::open(path)?.lookup(cell)? {
+                        Some((_, val)) => println!("{label}: {val}"),
+                        None => {
+                            warn!("no entry for {idx} in {label}");
+                            misses += 1;
+                        }
+                    }
+                }
+                if misses > 0 {
+                    return Err(Miss(format!(
+                        "no entry for {idx} in {misses} of {} maps",
+                        maps.len()
+                    ))
+                    .into());
+                }
+            }
+
+            Cmd::Repl { map, base } => {
+                let mut region_map = RegionMap::open(map)?;
+                // Prompt on stderr so that piped output is just values.
+                let prompt = std::io::stdin().is_terminal();
+                let mut lines = std::io::stdin().lines();
+                loop {
+                    if prompt {
+                        eprint!("> ");
+                    }
+                    let Some(line) = lines.next().transpose()? else {
+                        break;
+                    };
+                    let idx = line.trim();
+                    match idx {
+                        "" => continue,
+                        ":q" => break,
+                        _ => (),
+                    }
+                    // A typo shouldn't end the session.
+                    let lookup = (|| -> Result<_> {
+                        let (cell_idx, cell) = parse_cell(idx, base)?;
+                        Ok((cell_idx, region_map.lookup(cell)?))
+                    })();
+                    match lookup {
+                        Ok((cell_idx, Some((matched, val)))) => {
+                            print_value(cell_idx, &val, Some(matched), false)
+                        }
+                        Ok((_, None)) => println!("no entry for {idx}"),
+                        Err(e) => eprintln!("{idx}: {e}"),
+                    }
+                }
+            }
+
+            Cmd::LookupCoord {
+                map,
+                lat,
+                lng,
+                json,
+                show_cell,
+                verify,
+                property,
+            } => {
+                let mut region_map = RegionMap::open(map)?;
+                let latlng = LatLng::new(lat, lng)?;
+                let cell = region_map.latlng_to_cell(latlng)?;
+                let (matched, val) = region_map
+                    .lookup(cell)?
+                    .ok_or_else(|| Miss(format!("no entry for {lat},{lng}")))?;
+                if let Some(world) = verify {
+                    let features = read_world(&world, None, None)?;
+                    if !lwreg::region_contains(features, property.as_deref(), &val, latlng)? {
+                        return Err(Miss(format!(
+                            "{lat},{lng} is in a cell of {val}, but outside its polygons"
+                        ))
+                        .into());
+                    }
+                }
+                print_value(cell.into_raw(), &val, show_cell.then_some(matched), json);
+            }
+
+            Cmd::LookupCsv {
+                map,
+                csv: csv_path,
+                lat_col,
+                lng_col,
+            } => {
+                let mut region_map = RegionMap::open(map)?;
+                let mut rdr = csv::Reader::from_path(csv_path)?;
+                let mut wtr = csv::Writer::from_writer(std::io::stdout().lock());
+
+                let headers = rdr.headers()?.clone();
+                let column = |name: &str| {
+                    headers
+                        .iter()
+                        .position(|header| header == name)
+                        .ok_or_else(|| anyhow!("no column named {name}"))
+                };
+                let (lat_idx, lng_idx) = (column(&lat_col)?, column(&lng_col)?);
+                let mut out_headers = headers.clone();
+                out_headers.push_field("region");
+                wtr.write_record(&out_headers)?;
+
+                // As with Lookup, a miss leaves the region empty
+                // rather than aborting the batch.
+                let (mut rows, mut misses) = (0, 0);
+                for record in rdr.records() {
+                    let mut record = record?;
+                    rows += 1;
+                    let field = |idx: usize| -> Result<f64> {
+                        Ok(record.get(idx).unwrap_or_default().trim().parse()?)
+                    };
+                    let latlng = LatLng::new(field(lat_idx)?, field(lng_idx)?)?;
+                    let cell = region_map.latlng_to_cell(latlng)?;
+                    match region_map.lookup(cell)? {
+                        Some((_, val)) => record.push_field(&val),
+                        None => {
+                            warn!("no entry for row {rows}");
+                            misses += 1;
+                            record.push_field("");
+                        }
+                    }
+                    wtr.write_record(&record)?;
+                }
+                wtr.flush()?;
+
+                if misses > 0 {
+                    return Err(Miss(format!("no entry for {misses} of {rows} rows")).into());
+                }
+            }
+
+            Cmd::Ring { map, idx, k, base } => {
+                let (raw, _) = parse_cell(&idx, base)?;
+                let center = CellIndex::try_from(raw)?;
+                let mut region_map = RegionMap::open(map)?;
+                print_region_counts(&mut region_map, center.grid_disk::<Vec<_>>(k))?;
+            }
+
+            Cmd::Bbox {
+                map,
+                min_lat,
+                min_lng,
+                max_lat,
+                max_lng,
+                containment,
+            } => {
+                let mut region_map = RegionMap::open(map)?;
+                let res = region_map
+                    .resolution()
+                    .ok_or_else(|| anyhow!("map has no stored resolution"))?;
+                let ring = vec![
+                    vec![min_lng, min_lat],
+                    vec![max_lng, min_lat],
+                    vec![max_lng, max_lat],
+                    vec![min_lng, max_lat],
+                    vec![min_lng, min_lat],
+                ];
+                let bbox = geojson::Geometry::new(geojson::Value::Polygon(vec![ring]));
+                let config = PolyfillConfig::new(res).containment_mode(containment.into());
+                let cells = Geometry::try_from(&bbox)?.to_cells(config);
+                print_region_counts(&mut region_map, cells)?;
+            }
+
+            Cmd::Query {
+                map,
+                geojson,
+                containment,
+            } => {
+                let mut region_map = RegionMap::open(map)?;
+                let res = region_map
+                    .resolution()
+                    .ok_or_else(|| anyhow!("map has no stored resolution"))?;
+                let geometries = match GeoJson::from_reader(BufReader::new(File::open(geojson)?))? {
+                    GeoJson::Geometry(geometry) => vec![geometry],
+                    GeoJson::Feature(feature) => feature.geometry.into_iter().collect(),
+                    GeoJson::FeatureCollection(collection) => collection
+                        .features
+                        .into_iter()
+                        .filter_map(|feature| feature.geometry)
+                        .collect(),
+                };
+                let config = PolyfillConfig::new(res).containment_mode(containment.into());
+                let mut cells = Vec::new();
+                for geometry in &geometries {
+                    cells.extend(Geometry::try_from(geometry)?.to_cells(config));
+                }
+                cells.sort_unstable();
+                cells.dedup();
+                print_region_counts(&mut region_map, cells)?;
+            }
+
+            Cmd::Dump {
+                map,
+                region,
+                offset,
+                limit,
+            } => {
+                let mut region_map = RegionMap::open(map)?;
+                let lut_idx = region_map
+                    .lut_index(&region)?
+                    .ok_or_else(|| anyhow!("no region named {region}"))?;
+                let cells = region_map.iter_indices()?.filter_map(|entry| match entry {
+                    Ok((cell, idx)) if idx == lut_idx => Some(Ok(cell)),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                });
+                for (n, cell) in cells.enumerate() {
+                    // Skipped cells are still read so that errors
+                    // among them aren't lost.
+                    let cell = cell?;
+                    if n < offset {
+                        continue;
+                    }
+                    if limit.is_some_and(|limit| n - offset >= limit) {
+                        break;
+                    }
+                    println!("{:x}", cell.into_raw());
+                }
+            }
+
+            Cmd::Sample { map, n, seed } => {
+                let mut region_map = RegionMap::open(map)?;
+                let mut rng = match seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                };
+                // Reservoir sampling, so only `n` cells are held
+                // however large the map is. Each is kept with its
+                // position in the tree to print them in tree order.
+                let mut picked: Vec<(usize, Cell, u16)> = Vec::with_capacity(n);
+                for (pos, entry) in region_map.iter_indices()?.enumerate() {
+                    let (cell, lut_idx) = entry?;
+                    if picked.len() < n {
+                        picked.push((pos, cell, lut_idx));
+                    } else if let Some(slot) = picked.get_mut(rng.gen_range(0..=pos)) {
+                        *slot = (pos, cell, lut_idx);
+                    }
+                }
+                picked.sort_unstable_by_key(|&(pos, _, _)| pos);
+                for (_, cell, lut_idx) in picked {
+                    println!("{:x}: {}", cell.into_raw(), region_map.resolve(lut_idx)?);
+                }
+            }
+
+            Cmd::Count { map, expand, area } => {
+                let mut region_map = RegionMap::open(map)?;
+                let res = match (expand, region_map.resolution()) {
+                    (true, None) => return Err(anyhow!("map has no stored resolution")),
+                    (_, res) => res,
+                };
+                // LuT index -> (stored cells, expanded cells)
+                let mut counts: BTreeMap<u16, (u64, u64)> = BTreeMap::new();
+                // LuT index -> km²
+                let mut areas: HashMap<u16, f64> = HashMap::new();
+                for entry in region_map.iter_indices()? {
+                    let (cell, lut_idx) = entry?;
+                    let count = counts.entry(lut_idx).or_default();
+                    count.0 += 1;
+                    if let (true, Some(res)) = (expand, res) {
+                        count.1 += CellIndex::try_from(cell.into_raw())?.children_count(res);
+                    }
+                    if area {
+                        // Each cell's own area, so compacted parents
+                        // count at their resolution.
+                        *areas.entry(lut_idx).or_default() +=
+                            CellIndex::try_from(cell.into_raw())?.area_km2();
+                    }
+                }
+                let mut counts: Vec<(u16, (u64, u64))> = counts.into_iter().collect();
+                counts.sort_by(|a, b| b.1.cmp(&a.1));
+                for (lut_idx, (stored, expanded)) in counts {
+                    let region = region_map.resolve(lut_idx)?;
+                    let mut line = format!("{region}: {stored}");
+                    if expand {
+                        line += &format!(" ({expanded} at res {})", res.unwrap());
+                    }
+                    if area {
+                        line += &format!(", {:.1} km²", areas[&lut_idx]);
+                    }
+                    println!("{line}");
+                }
+            }
+
+            Cmd::Histogram { map, out } => {
+                let mut region_map = RegionMap::open(map)?;
+                let res = region_map
+                    .resolution()
+                    .ok_or_else(|| anyhow!("map has no stored resolution"))?;
+                let lut = region_map.read_lut()?;
+                // LuT index -> (cells at `res`, km²)
+                let mut rows = vec![(0_u64, 0_f64); lut.len()];
+                for entry in region_map.iter_indices()? {
+                    let (cell, lut_idx) = entry?;
+                    let cell = CellIndex::try_from(cell.into_raw())?;
+                    let row = &mut rows[usize::from(lut_idx)];
+                    row.0 += cell.children_count(res);
+                    row.1 += cell.area_km2();
+                }
+                let mut wtr = csv::Writer::from_path(out)?;
+                wtr.write_record(["region", "cell_count", "area_km2"])?;
+                for (region, (count, area)) in lut.iter().zip(&rows) {
+                    wtr.write_record([region, &count.to_string(), &format!("{area:.3}")])?;
+                }
+                let count: u64 = rows.iter().map(|row| row.0).sum();
+                let area: f64 = rows.iter().map(|row| row.1).sum();
+                wtr.write_record([
+                    "total",
+                    count.to_string().as_str(),
+                    format!("{area:.3}").as_str(),
+                ])?;
+                wtr.flush()?;
+            }
+
+            Cmd::Aggregate { map, resolution } => {
+                let mut region_map = RegionMap::open(map)?;
+                let map_res = region_map
+                    .resolution()
+                    .ok_or_else(|| anyhow!("map has no stored resolution"))?;
+                if resolution > map_res {
+                    return Err(anyhow!(
+                        "res {resolution} is finer than the map's res {map_res}"
+                    ));
+                }
+                // Parent cell -> LuT index -> cells at `map_res`
+                let mut parents: BTreeMap<CellIndex, BTreeMap<u16, u64>> = BTreeMap::new();
+                for entry in region_map.iter_indices()? {
+                    let (cell, lut_idx) = entry?;
+                    let cell = CellIndex::try_from(cell.into_raw())?;
+                    // A compacted cell coarser than `resolution` spans
+                    // several parents, so each of its children at
+                    // `resolution` counts towards its own.
+                    if cell.resolution() < resolution {
+                        for child in cell.children(resolution) {
+                            *parents
+                                .entry(child)
+                                .or_default()
+                                .entry(lut_idx)
+                                .or_default() += child.children_count(map_res);
+                        }
+                    } else {
+                        let parent = cell
+                            .parent(resolution)
+                            .ok_or_else(|| anyhow!("no parent at res {resolution} for {cell}"))?;
+                        *parents
+                            .entry(parent)
+                            .or_default()
+                            .entry(lut_idx)
+                            .or_default() += cell.children_count(map_res);
+                    }
+                }
+                for (parent, counts) in parents {
+                    let mut counts: Vec<(u16, u64)> = counts.into_iter().collect();
+                    counts.sort_by(|a, b| b.1.cmp(&a.1));
+                    let regions = counts
+                        .into_iter()
+                        .map(|(lut_idx, count)| {
+                            Ok(format!("{} ({count})", region_map.resolve(lut_idx)?))
+                        })
+                        .collect::<Result<Vec<String>>>()?;
+                    println!("{parent}: {}", regions.join(", "));
+                }
+            }
+
+            Cmd::ToH3idz {
+                map,
+                region,
+                out,
+                resolution,
+            } => {
+                let mut region_map = RegionMap::open(map)?;
+                let lut_idx = region_map
+                    .lut_index(&region)?
+                    .ok_or_else(|| anyhow!("no region named {region}"))?;
+                let mut wtr = GzEncoder::new(
+                    BufWriter::new(File::create(&out)?),
+                    flate2::Compression::default(),
+                );
+                let mut count = 0_u64;
+                for entry in region_map.iter_indices()? {
+                    let (cell, idx) = entry?;
+                    if idx != lut_idx {
+                        continue;
+                    }
+                    let cell = CellIndex::try_from(cell.into_raw())?;
+                    let Some(res) = resolution else {
+                        wtr.write_u64::<LE>(cell.into())?;
+                        count += 1;
+                        continue;
+                    };
+                    if cell.resolution() > res {
+                        return Err(anyhow!("{cell} is finer than res {res}"));
+                    }
+                    for child in cell.children(res) {
+                        wtr.write_u64::<LE>(child.into())?;
+                        count += 1;
+                    }
+                }
+                wtr.finish()?.flush()?;
+                log::info!("wrote {count} cells to {}", out.display());
+            }
+
+            Cmd::Export {
+                map,
+                region,
+                out,
+                compact,
+            } => {
+                let mut region_map = RegionMap::open(map)?;
+                let lut_idx = region_map
+                    .lut_index(&region)?
+                    .ok_or_else(|| anyhow!("no region named {region}"))?;
+                let map_res = region_map.resolution();
+                let mut cells = Vec::new();
+                for entry in region_map.iter_indices()? {
+                    let (cell, idx) = entry?;
+                    if idx == lut_idx {
+                        cells.push(CellIndex::try_from(cell.into_raw())?);
+                    }
+                }
+
+                let features = if compact {
+                    // Dissolving needs cells of one resolution, but a
+                    // compacted map mixes them, so expand the coarser
+                    // ones to the map's resolution, or the finest
+                    // stored if it has none.
+                    let finest = cells.iter().map(|cell| cell.resolution()).max();
+                    if let Some(res) = map_res.or(finest) {
+                        cells = cells.iter().flat_map(|cell| cell.children(res)).collect();
+                    }
+                    let mut properties = JsonObject::new();
+                    properties.insert("region".to_owned(), Value::String(region));
+                    let geometry = geojson::Value::from(&cells.to_geom(true)?);
+                    vec![to_feature(geometry, properties)]
+                } else {
+                    cells
+                        .into_iter()
+                        .map(|cell| {
+                            let mut ring: Vec<Vec<f64>> = cell
+                                .boundary()
+                                .iter()
+                                .map(|ll| vec![ll.lng(), ll.lat()])
+                                .collect();
+                            ring.push(ring[0].clone());
+                            let mut properties = JsonObject::new();
+                            properties.insert("cell".to_owned(), Value::String(cell.to_string()));
+                            to_feature(geojson::Value::Polygon(vec![ring]), properties)
+                        })
+                        .collect()
+                };
+
+                let feature_collection = FeatureCollection {
+                    bbox: None,
+                    features,
+                    foreign_members: None,
+                };
+                serde_json::to_writer(File::create(out)?, &feature_collection)?;
+            }
+
+            Cmd::Diff { a, b, detail } => {
+                let mut a = RegionMap::open(a)?;
+                let mut b = RegionMap::open(b)?;
+                let a_lut = a.read_lut()?;
+                let b_lut = b.read_lut()?;
+                // A cell stored compacted in one map and expanded in the
+                // other isn't a difference, so each stored cell is
+                // compared against whatever covers it in the other map,
+                // and only the parts nothing covers count as missing.
+                let mut a_map: HexTreeMap<u16> = HexTreeMap::new();
+                for entry in a.iter_indices()? {
+                    let (cell, idx) = entry?;
+                    a_map.insert(cell, idx);
+                }
+                let mut b_map: HexTreeMap<u16> = HexTreeMap::new();
+                for entry in b.iter_indices()? {
+                    let (cell, idx) = entry?;
+                    b_map.insert(cell, idx);
+                }
+                let (mut only_a, mut only_b, mut changed) = (0_u64, 0_u64, 0_u64);
+                let mut compare = |cell: Cell, a_idx: u16, b_idx: u16| {
+                    let (a_val, b_val) = (&a_lut[usize::from(a_idx)], &b_lut[usize::from(b_idx)]);
+                    if a_val != b_val {
+                        changed += 1;
+                        if detail {
+                            println!("~ {:x} {a_val} -> {b_val}", cell.into_raw());
+                        }
+                    }
+                };
+
+                for (cell, &a_idx) in a_map.iter() {
+                    match b_map.get(cell) {
+                        Some((_, &b_idx)) => compare(cell, a_idx, b_idx),
+                        None => {
+                            for missing in uncovered_within(&b_map, cell)? {
+                                only_a += 1;
+                                if detail {
+                                    let a_val = &a_lut[usize::from(a_idx)];
+                                    println!("- {:x} {a_val}", missing.into_raw());
+                                }
+                            }
+                        }
+                    }
+                }
+
+                for (cell, &b_idx) in b_map.iter() {
+                    match a_map.get(cell) {
+                        // Compared above, from a's side.
+                        Some((a_cell, _)) if a_cell == cell => (),
+                        // A coarser cell of a's, which b has nothing at
+                        // or above, so it wasn't compared above.
+                        Some((_, &a_idx)) => compare(cell, a_idx, b_idx),
+                        None => {
+                            for missing in uncovered_within(&a_map, cell)? {
+                                only_b += 1;
+                                if detail {
+                                    let b_val = &b_lut[usize::from(b_idx)];
+                                    println!("+ {:x} {b_val}", missing.into_raw());
+                                }
+                            }
+                        }
+                    }
+                }
+
+                println!("only in a: {only_a}");
+                println!("only in b: {only_b}");
+                println!("changed:   {changed}");
+            }
+
+            Cmd::Verify { map } => {
+                let report = lwreg::verify(&map);
+                for (check, result) in &report {
+                    match result {
+                        Ok(()) => println!("ok    {check}"),
+                        Err(e) => println!("FAIL  {check}: {e}"),
+                    }
+                }
+                if report.iter().any(|(_, result)| result.is_err()) {
+                    return Err(anyhow!("{} failed verification", map.display()));
+                }
+            }
+
+            Cmd::Info { map, json, pretty } => {
+                let mut region_map = RegionMap::open(map)?;
+                let lut = region_map.read_lut()?;
+                let header = region_map.header();
+                let compression = format!("{:?}", region_map.compression()).to_lowercase();
+                let value_width = format!("{:?}", header.value_width).to_lowercase();
+                if json {
+                    let output = serde_json::json!({
+                        "version": header.version,
+                        "resolution": header.resolution.map(u8::from),
+                        "cells": header.cell_count,
+                        "gzip_lut": header.gzip_lut,
+                        "split_lut": header.split_lut,
+                        "value_width": header.has_lut.then_some(&value_width),
+                        "compression": compression,
+                        "h3o_version": header.h3o_version,
+                        "hextree_version": header.hextree_version,
+                        // Null for maps of raw values.
+                        "lut": header.has_lut.then_some(&lut),
+                    });
+                    if pretty {
+                        println!("{}", serde_json::to_string_pretty(&output)?);
+                    } else {
+                        println!("{output}");
+                    }
+                } else {
+                    let resolution = header
+                        .resolution
+                        .map_or_else(|| "unknown".to_owned(), |res| res.to_string());
+                    println!("version:    {}", header.version);
+                    println!("resolution: {resolution}");
+                    println!("cells:      {}", header.cell_count);
+                    println!("h3o:        {}", header.h3o_version);
+                    println!("hextree:    {}", header.hextree_version);
+                    if region_map.compression() != Compression::None {
+                        println!("compressed: {compression}");
+                    }
+                    if !header.has_lut {
+                        println!("values:     raw");
+                        return Ok(());
+                    }
+                    println!("values:     {value_width}");
+                    match (header.gzip_lut, header.split_lut) {
+                        (true, true) => println!("lut:        split, gzip"),
+                        (true, false) => println!("lut:        gzip"),
+                        (false, true) => println!("lut:        split"),
+                        (false, false) => (),
+                    }
+                    println!("regions:    {}", lut.len());
+                    for (idx, val) in lut.iter().enumerate() {
+                        match serde_json::from_str::<Value>(val) {
+                            Ok(parsed) if pretty && (parsed.is_object() || parsed.is_array()) => {
+                                let pretty = serde_json::to_string_pretty(&parsed)?;
+                                println!("  {idx}: {}", pretty.replace('\n', "\n    "));
+                            }
+                            _ => println!("  {idx}: {val}"),
+                        }
+                    }
+                }
+            }
+
+            #[cfg(feature = "serve")]
+            Cmd::Serve { map, bind } => serve(&map, &bind)?,
+
+            Cmd::Completions { shell } => {
+                let mut cmd = Cli::command();
+                let name = cmd.get_name().to_owned();
+                clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A lookup that ran cleanly but found no entry, which exits with
+/// [`MISS_EXIT_CODE`] rather than as an error.
+#[derive(Debug)]
+struct Miss(String);
+
+impl std::fmt::Display for Miss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Miss {}
+
+/// Exit code for a [`Miss`], leaving 1 to mean an error.
+const MISS_EXIT_CODE: u8 = 2;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    env_logger::Builder::new()
+        .filter_level(cli.log_level())
+        .init();
+    if cli.trace {
+        // Spans are printed as they close, with the time spent in
+        // them. Logging stays with env_logger.
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(io::stderr)
+            .with_max_level(tracing::Level::INFO)
+            .with_span_events(FmtSpan::CLOSE)
+            .finish();
+        tracing::subscriber::set_global_default(subscriber).expect("no other subscriber is set");
+    }
+    let result = match cli.threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(Into::into)
+            .and_then(|pool| pool.install(|| cli.cmd.run(cli.dry_run))),
+        None => cli.cmd.run(cli.dry_run),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if e.is::<Miss>() => {
+            eprintln!("{e}");
+            ExitCode::from(MISS_EXIT_CODE)
+        }
+        // As returning the error from `main` would print it.
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,78 @@
+//! Python bindings, built with the `python` feature.
+//!
+//! Build with e.g. `maturin build --features python`, then:
+//!
+//! ```python
+//! import lwreg
+//! regions = lwreg.RegionMap.open("world.disktree")
+//! regions.lookup("8a2a1072b59ffff")
+//! ```
+
+use crate::Error;
+use hextree::Cell;
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::path::PathBuf;
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err.into(),
+            other => PyValueError::new_err(other.to_string()),
+        }
+    }
+}
+
+/// An h3 index as Python might hand it over.
+#[derive(FromPyObject)]
+enum CellArg {
+    Int(u64),
+    Hex(String),
+}
+
+impl CellArg {
+    fn to_cell(&self) -> PyResult<Cell> {
+        let raw = match self {
+            CellArg::Int(raw) => *raw,
+            CellArg::Hex(hex) => {
+                let hex = hex.strip_prefix("0x").unwrap_or(hex);
+                u64::from_str_radix(hex, 16)
+                    .map_err(|e| PyValueError::new_err(format!("bad h3 index {hex}: {e}")))?
+            }
+        };
+        Cell::from_raw(raw).map_err(|e| PyValueError::new_err(format!("bad h3 index {raw:x}: {e}")))
+    }
+}
+
+/// A region map opened from disk.
+#[pyclass(name = "RegionMap")]
+struct PyRegionMap {
+    inner: crate::RegionMap,
+}
+
+#[pymethods]
+impl PyRegionMap {
+    /// Opens the map at `path`.
+    #[staticmethod]
+    fn open(path: PathBuf) -> PyResult<Self> {
+        Ok(Self {
+            inner: crate::RegionMap::open(path)?,
+        })
+    }
+
+    /// Returns the region containing `cell`, an h3 index as an int or
+    /// a hex string, or `None` if the map has no entry for it.
+    fn lookup(&mut self, py: Python<'_>, cell: CellArg) -> PyResult<Option<String>> {
+        let cell = cell.to_cell()?;
+        let inner = &mut self.inner;
+        // Lookups seek and read the file, so let other Python threads
+        // run meanwhile.
+        let found = py.allow_threads(|| inner.lookup(cell))?;
+        Ok(found.map(|(_, val)| val))
+    }
+}
+
+#[pymodule]
+fn lwreg(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyRegionMap>()?;
+    Ok(())
+}
@@ -0,0 +1,609 @@
+use crate::{
+    format::{
+        decode_lut, read_header, read_lut, read_lut_bytes, read_lut_entry, read_lut_offsets,
+        read_old_lut, read_trailer, string_lut_offsets, Header, Section, Trailer,
+        CELL_COUNT_VERSION, OFFSET_TABLE_VERSION,
+    },
+    Compression, Error, Result, ValueWidth,
+};
+use byteorder::{LittleEndian as LE, ReadBytesExt};
+use h3o::{LatLng, Resolution};
+use hextree::{disktree::DiskTree, Cell};
+#[cfg(not(target_arch = "wasm32"))]
+use memmap2::Mmap;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+/// A region map opened from disk.
+///
+/// `R` is the underlying reader: a [`MapFile`] for [`RegionMap::open`],
+/// or a memory-mapped buffer for [`RegionMap::open_mmap`].
+///
+/// Only the LuT's offset table is read up front. Entries are read
+/// from disk as they're resolved, so opening a map with a large LuT
+/// to do a few lookups stays cheap. [`RegionMap::iter`] is the
+/// exception, reading the whole LuT the first time it's called, as is
+/// a gzipped or split LuT, which is read whole on open. So is the LuT
+/// of a file from before version 5, which has no offset table.
+pub struct RegionMap<R = MapFile> {
+    header: Header,
+    compression: Compression,
+    disktree: DiskTree<Section<R>>,
+    /// Where the disktree starts, after the header.
+    disktree_pos: u64,
+    lut_rdr: R,
+    lut_trailer: Trailer,
+    lut_offsets: Vec<u64>,
+    /// The whole LuT, once something has needed it.
+    lut: OnceLock<Vec<String>>,
+    /// The decompressed LuT, for a gzipped one, or the contents of a
+    /// split one's file, which entries are read from instead of
+    /// `lut_rdr`.
+    lut_bytes: Option<Vec<u8>>,
+}
+
+/// A map file as [`RegionMap::open`] reads it: from disk, or from
+/// memory once a compressed file has been decompressed.
+pub enum MapFile {
+    File(File),
+    Memory(Cursor<Arc<[u8]>>),
+}
+
+impl MapFile {
+    /// Opens the file at `path`, decompressing it into memory if it's
+    /// compressed.
+    pub(crate) fn open(path: &Path) -> Result<(Self, Compression)> {
+        let mut file = File::open(path)?;
+        let mut prefix = Vec::with_capacity(4);
+        (&mut file).take(4).read_to_end(&mut prefix)?;
+        file.rewind()?;
+        let compression = Compression::detect(&prefix);
+        if compression == Compression::None {
+            return Ok((Self::File(file), compression));
+        }
+        let bytes: Arc<[u8]> = compression.decompress(file)?.into();
+        Ok((Self::Memory(Cursor::new(bytes)), compression))
+    }
+
+    /// Opens a second, independent reader over the same file.
+    fn reopen(&self, path: &Path) -> Result<Self> {
+        match self {
+            Self::File(_) => Ok(Self::File(File::open(path)?)),
+            Self::Memory(cursor) => Ok(Self::Memory(Cursor::new(cursor.get_ref().clone()))),
+        }
+    }
+}
+
+impl Read for MapFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::File(file) => file.read(buf),
+            Self::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for MapFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            Self::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+impl RegionMap {
+    /// Opens the map at `path`.
+    ///
+    /// A [compressed](Compression) map is decompressed into memory
+    /// first, and read from there. A map with a split LuT has it read
+    /// from the `.lut` file next to it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let (tree_rdr, compression) = MapFile::open(path)?;
+        let lut_rdr = tree_rdr.reopen(path)?;
+        let mut region_map = Self::from_readers(tree_rdr, lut_rdr)?;
+        region_map.compression = compression;
+        region_map.load_sibling_lut(path)?;
+        Ok(region_map)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl RegionMap<Cursor<Mmap>> {
+    /// Opens the map at `path` by memory-mapping it.
+    ///
+    /// Lookups then read straight from the page cache instead of
+    /// going through a syscall per seek and read, which pays off for
+    /// many lookups against one map.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it is mapped;
+    /// doing so is undefined behavior. Regenerating a map in place
+    /// while a process has it open is exactly that, so write new maps
+    /// to a fresh path and rename them over the old one.
+    ///
+    /// A [compressed](Compression) map can't be read in place, so
+    /// this fails with [`Error::Compressed`] for one.
+    pub unsafe fn open_mmap<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        // Two mappings of one file share the page cache, so this
+        // costs address space but not memory.
+        let tree_mmap = Mmap::map(&file)?;
+        if Compression::detect(&tree_mmap) != Compression::None {
+            return Err(Error::Compressed);
+        }
+        let lut_mmap = Mmap::map(&file)?;
+        let mut region_map = Self::from_readers(Cursor::new(tree_mmap), Cursor::new(lut_mmap))?;
+        region_map.load_sibling_lut(path)?;
+        Ok(region_map)
+    }
+
+    /// Looks up `cell` as for [`RegionMap::lookup`], but through a
+    /// shared reference, so that one mapped map can serve any number
+    /// of threads at once.
+    ///
+    /// A disktree seeks through its reader, so lookups through it need
+    /// `&mut self`. Over a mapping, though, a reader is only a cursor
+    /// into memory every thread shares, so this reads through a
+    /// disktree of its own over the one mapping.
+    pub fn lookup_shared(&self, cell: Cell) -> Result<Option<(Cell, &str)>> {
+        let lut = self.shared_lut()?;
+        let mut disktree = self.shared_disktree()?;
+        let Some((matched, rdr)) = disktree.seek_to_cell(coerce(cell, self.header.resolution)?)?
+        else {
+            return Ok(None);
+        };
+        let lut_idx = check_index(self.header.value_width.read(rdr)?, lut.len())?;
+        Ok(Some((matched, lut[usize::from(lut_idx)].as_str())))
+    }
+
+    /// Looks up each of `cells` in parallel, returning their values in
+    /// order, or `None` for cells the map has no entry for. Cells are
+    /// matched as for [`RegionMap::lookup_index`].
+    ///
+    /// As for [`RegionMap::lookup_shared`], each rayon worker reads
+    /// through a disktree of its own over the one mapping.
+    pub fn lookup_many(&self, cells: &[Cell]) -> Result<Vec<Option<&str>>> {
+        let lut = self.shared_lut()?;
+        let resolution = self.header.resolution;
+        let width = self.header.value_width;
+        cells
+            .par_iter()
+            .map_init(
+                || None,
+                |disktree, &cell| {
+                    // Opened on a worker's first cell, so that failing
+                    // to is an error for that cell rather than a panic.
+                    let disktree = match disktree {
+                        Some(disktree) => disktree,
+                        None => disktree.insert(self.shared_disktree()?),
+                    };
+                    let Some((_, rdr)) = disktree.seek_to_cell(coerce(cell, resolution)?)? else {
+                        return Ok(None);
+                    };
+                    let lut_idx = check_index(width.read(rdr)?, lut.len())?;
+                    Ok(Some(lut[usize::from(lut_idx)].as_str()))
+                },
+            )
+            .collect()
+    }
+
+    /// Returns a disktree over the mapping, read through a cursor of
+    /// its own.
+    fn shared_disktree(&self) -> Result<DiskTree<Section<Cursor<&[u8]>>>> {
+        // The LuT's reader maps the whole file too.
+        let file: &[u8] = self.lut_rdr.get_ref();
+        Ok(DiskTree::from_reader(Section::new(
+            Cursor::new(file),
+            self.disktree_pos,
+        ))?)
+    }
+
+    /// Returns the whole LuT, reading it into memory through a cursor
+    /// of its own the first time.
+    fn shared_lut(&self) -> Result<&[String]> {
+        self.checked_lut_len()?;
+        if let Some(lut) = self.lut.get() {
+            return Ok(lut);
+        }
+        let lut = match &self.lut_bytes {
+            Some(bytes) => decode_lut(bytes)?,
+            None => read_lut(
+                &mut Cursor::new(self.lut_rdr.get_ref().as_ref()),
+                &self.lut_trailer,
+                self.header.gzip_lut,
+            )?,
+        };
+        Ok(self.lut.get_or_init(|| lut))
+    }
+}
+
+impl<R: Read + Seek> RegionMap<R> {
+    /// Reads a map from two independent readers over the same file,
+    /// one for the disktree and one for the LuT.
+    ///
+    /// The LuT's own reader lets entries be resolved without
+    /// disturbing the disktree's position, e.g. while iterating. A
+    /// split LuT isn't in the file, so load it with
+    /// [`RegionMap::load_lut`] before resolving anything.
+    pub fn from_readers(mut tree_rdr: R, mut lut_rdr: R) -> Result<Self> {
+        let mut header = read_header(&mut tree_rdr)?;
+        let disktree_pos = tree_rdr.stream_position()?;
+        let mut disktree = DiskTree::from_reader(Section::new(tree_rdr, disktree_pos))?;
+        if header.version < CELL_COUNT_VERSION {
+            for entry in disktree.iter()? {
+                entry?;
+                header.cell_count += 1;
+            }
+        }
+
+        if header.version < OFFSET_TABLE_VERSION {
+            // Older LuTs have no offset table, but they only ever held
+            // strings, whose positions can be found by reading them.
+            let (lut_trailer, bytes) = read_old_lut(&mut lut_rdr, header.version)?;
+            return Ok(Self {
+                header,
+                compression: Compression::None,
+                disktree,
+                disktree_pos,
+                lut_rdr,
+                lut_trailer,
+                lut_offsets: string_lut_offsets(&bytes)?,
+                lut: OnceLock::new(),
+                lut_bytes: Some(bytes),
+            });
+        }
+        let lut_trailer = read_trailer(&mut lut_rdr)?;
+        // Maps of raw values have an empty LuT, and a split map's is
+        // elsewhere, so there's nothing to read for either.
+        let (lut_offsets, lut_bytes) = if !header.has_lut || header.split_lut {
+            (Vec::new(), None)
+        } else if header.gzip_lut {
+            // Entries can't be read out of the middle of a gzip
+            // stream, so decompress it all now and resolve from
+            // memory.
+            let (offsets, bytes) = read_lut_into_memory(&mut lut_rdr, &lut_trailer, true)?;
+            (offsets, Some(bytes))
+        } else {
+            let lut_span = lut_trailer.lut_pos..lut_trailer.offsets_pos;
+            (
+                read_lut_offsets(&mut lut_rdr, &lut_trailer, lut_span)?,
+                None,
+            )
+        };
+        Ok(Self {
+            header,
+            compression: Compression::None,
+            disktree,
+            disktree_pos,
+            lut_rdr,
+            lut_trailer,
+            lut_offsets,
+            lut: OnceLock::new(),
+            lut_bytes,
+        })
+    }
+
+    /// Reads a split map's LuT from `rdr`, over the `.lut` file
+    /// written by [`write_split_region_map`].
+    ///
+    /// It's an error to load one into a map whose LuT isn't split.
+    /// The whole LuT is read into memory and checked against its CRC.
+    /// Nothing ties a `.lut` file to its map, but a LuT with too few
+    /// entries for the map is caught as values are read.
+    ///
+    /// [`write_split_region_map`]: crate::write_split_region_map
+    pub fn load_lut<L: Read + Seek>(&mut self, mut rdr: L) -> Result<()> {
+        if !self.header.split_lut {
+            return Err(Error::LutNotSplit);
+        }
+        let trailer = read_trailer(&mut rdr)?;
+        // The LuT is the file's only content, so uncompressed offsets
+        // into the file are offsets into it too.
+        if trailer.lut_pos != 0 {
+            return Err(Error::Corrupt(format!(
+                "split LuT starts at {} rather than 0",
+                trailer.lut_pos
+            )));
+        }
+        let (offsets, bytes) = read_lut_into_memory(&mut rdr, &trailer, self.header.gzip_lut)?;
+        self.lut_offsets = offsets;
+        self.lut_bytes = Some(bytes);
+        self.lut = OnceLock::new();
+        Ok(())
+    }
+
+    /// Loads a split LuT from the `.lut` file next to `path`, if the
+    /// map has one.
+    fn load_sibling_lut(&mut self, path: &Path) -> Result<()> {
+        if !self.header.split_lut {
+            return Ok(());
+        }
+        let lut_path = path.with_extension("lut");
+        let file = File::open(&lut_path).map_err(|e| {
+            Error::Io(io::Error::new(
+                e.kind(),
+                format!("can't open split LuT {}: {e}", lut_path.display()),
+            ))
+        })?;
+        self.load_lut(file)
+    }
+
+    /// Returns the map's header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Returns how the map file was compressed, as detected by
+    /// [`RegionMap::open`]. Maps read from other readers are taken to
+    /// be uncompressed.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Returns the resolution the map was built at, if known.
+    pub fn resolution(&self) -> Option<Resolution> {
+        self.header.resolution
+    }
+
+    /// Returns the cell containing `latlng` at the map's resolution.
+    pub fn latlng_to_cell(&self, latlng: LatLng) -> Result<Cell> {
+        let res = self
+            .header
+            .resolution
+            .ok_or_else(|| Error::ResolutionMismatch("map has no stored resolution".to_string()))?;
+        Cell::from_raw(latlng.to_cell(res).into()).map_err(|e| Error::CellParse(e.to_string()))
+    }
+
+    /// Returns the number of LuT entries.
+    pub fn lut_len(&self) -> usize {
+        self.lut_offsets.len()
+    }
+
+    /// Returns the LuT's length, or an error if the map's values don't
+    /// index a LuT.
+    fn checked_lut_len(&self) -> Result<usize> {
+        if !self.header.has_lut {
+            Err(Error::NoLut)
+        } else if self.header.split_lut && self.lut_bytes.is_none() {
+            Err(Error::LutNotLoaded)
+        } else {
+            Ok(self.lut_len())
+        }
+    }
+
+    /// Reads every LuT entry, in index order.
+    ///
+    /// This is the only read that checks the LuT's CRC, since doing
+    /// so means reading all of it. A gzipped or split LuT is checked
+    /// on open instead, when it's read into memory.
+    pub fn read_lut(&mut self) -> Result<Vec<String>> {
+        self.read_lut_as()
+    }
+
+    /// Reads every LuT entry as for [`RegionMap::read_lut`], decoding
+    /// them as `T`. See [`RegionMap::lookup_as`].
+    pub fn read_lut_as<T: DeserializeOwned>(&mut self) -> Result<Vec<T>> {
+        if self.header.split_lut {
+            self.checked_lut_len()?;
+        }
+        match &self.lut_bytes {
+            Some(bytes) => decode_lut(bytes),
+            None => read_lut(&mut self.lut_rdr, &self.lut_trailer, self.header.gzip_lut),
+        }
+    }
+
+    /// Returns the LuT index of `val`, if present.
+    pub fn lut_index(&mut self, val: &str) -> Result<Option<u16>> {
+        let lut = self.read_lut()?;
+        lut.iter()
+            .position(|entry| entry == val)
+            .map(|idx| {
+                // A future writer's u32 indices can reach entries a
+                // u16 can't.
+                u16::try_from(idx).map_err(|_| Error::TooManyValues {
+                    max: ValueWidth::U16.max_len(),
+                    found: lut.len(),
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns an iterator over every stored `(cell, lut_idx)` pair.
+    ///
+    /// Cells come in depth-first order over the tree, so each cell's
+    /// descendants directly follow it.
+    pub fn iter_indices(&mut self) -> Result<impl Iterator<Item = Result<(Cell, u16)>> + '_> {
+        let lut_len = self.checked_lut_len()?;
+        let width = self.header.value_width;
+        Ok(self.disktree.iter()?.map(move |entry| {
+            let (cell, rdr) = entry?;
+            Ok((cell, check_index(width.read(rdr)?, lut_len)?))
+        }))
+    }
+
+    /// Returns an iterator over every stored `(cell, value)` pair, in
+    /// the same order as [`RegionMap::iter_indices`].
+    ///
+    /// Cells are read from disk as the iterator advances, but the LuT
+    /// is read into memory first so values can be borrowed from it.
+    pub fn iter(&mut self) -> Result<impl Iterator<Item = Result<(Cell, &str)>> + '_> {
+        self.checked_lut_len()?;
+        if self.lut.get().is_none() {
+            let lut = self.read_lut()?;
+            self.lut.get_or_init(|| lut);
+        }
+        let lut: &[String] = self.lut.get().expect("LuT was just read");
+        let width = self.header.value_width;
+        Ok(self.disktree.iter()?.map(move |entry| {
+            let (cell, rdr) = entry?;
+            let lut_idx = check_index(width.read(rdr)?, lut.len())?;
+            Ok((cell, lut[usize::from(lut_idx)].as_str()))
+        }))
+    }
+
+    /// Reads the LuT entry at `lut_idx`.
+    pub fn resolve(&mut self, lut_idx: u16) -> Result<String> {
+        let idx = usize::from(check_index(u32::from(lut_idx), self.checked_lut_len()?)?);
+        if let Some(lut) = self.lut.get() {
+            return Ok(lut[idx].clone());
+        }
+        self.resolve_as(lut_idx)
+    }
+
+    /// Reads the LuT entry at `lut_idx`, decoding it as `T`. See
+    /// [`RegionMap::lookup_as`].
+    pub fn resolve_as<T: DeserializeOwned>(&mut self, lut_idx: u16) -> Result<T> {
+        let idx = usize::from(check_index(u32::from(lut_idx), self.checked_lut_len()?)?);
+        let pos = self.lut_offsets[idx];
+        let end = self.lut_offsets.get(idx + 1).copied();
+        match &self.lut_bytes {
+            Some(bytes) => {
+                let end = end.unwrap_or(bytes.len() as u64);
+                read_lut_entry(&mut Cursor::new(bytes), pos, end)
+            }
+            None => {
+                let end = end.unwrap_or(self.lut_trailer.offsets_pos);
+                read_lut_entry(&mut self.lut_rdr, pos, end)
+            }
+        }
+    }
+
+    /// Returns the LuT index stored for `cell`, if the map contains it
+    /// or any of its parents, along with the stored cell that matched.
+    ///
+    /// Cells finer than the map's resolution are coerced to their
+    /// parent at that resolution first. In a compacted map the matched
+    /// cell can be coarser still.
+    pub fn lookup_index(&mut self, cell: Cell) -> Result<Option<(Cell, u16)>> {
+        let lut_len = self.checked_lut_len()?;
+        let width = self.header.value_width;
+        let Some((matched, rdr)) = self.seek(cell)? else {
+            return Ok(None);
+        };
+        Ok(Some((matched, check_index(width.read(rdr)?, lut_len)?)))
+    }
+
+    /// Decodes the value stored for `cell` with `read`, returning it
+    /// along with the stored cell that matched.
+    ///
+    /// This is how to read maps written with [`write_map`]; `read`
+    /// should mirror the closure they were written with. Cells are
+    /// matched as for [`RegionMap::lookup_index`].
+    ///
+    /// [`write_map`]: crate::write_map
+    pub fn read_value<V, G>(&mut self, cell: Cell, read: G) -> Result<Option<(Cell, V)>>
+    where
+        G: FnOnce(&mut dyn Read) -> io::Result<V>,
+    {
+        let Some((matched, rdr)) = self.seek(cell)? else {
+            return Ok(None);
+        };
+        Ok(Some((matched, read(rdr)?)))
+    }
+
+    /// Returns the cell stored for `cell` in a map written with
+    /// [`write_cell_map`], along with the stored cell that matched,
+    /// as for [`RegionMap::lookup_index`].
+    ///
+    /// [`write_cell_map`]: crate::write_cell_map
+    pub fn lookup_cell(&mut self, cell: Cell) -> Result<Option<(Cell, Cell)>> {
+        if self.header.has_lut {
+            return Err(Error::HasLut);
+        }
+        let Some((matched, raw)) = self.read_value(cell, |rdr| rdr.read_u64::<LE>())? else {
+            return Ok(None);
+        };
+        let val = Cell::from_raw(raw).map_err(|e| Error::CellParse(e.to_string()))?;
+        Ok(Some((matched, val)))
+    }
+
+    /// Positions the disktree at the value for `cell`, coercing cells
+    /// finer than the map's resolution to their parent first.
+    fn seek(&mut self, cell: Cell) -> Result<Option<(Cell, &mut Section<R>)>> {
+        let cell = coerce(cell, self.header.resolution)?;
+        Ok(self.disktree.seek_to_cell(cell)?)
+    }
+
+    /// Returns the value for `cell` and the stored cell that matched,
+    /// as for [`RegionMap::lookup_index`].
+    pub fn lookup(&mut self, cell: Cell) -> Result<Option<(Cell, String)>> {
+        match self.lookup_index(cell)? {
+            Some((matched, lut_idx)) => Ok(Some((matched, self.resolve(lut_idx)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value for `cell` decoded as `T`, as for
+    /// [`RegionMap::lookup`].
+    ///
+    /// [`write_region_map`] takes a LuT of any serializable values, so
+    /// a map can hold structured values rather than strings. `T` must
+    /// decode what they were serialized as: bincode isn't
+    /// self-describing, so the wrong type either fails to decode or
+    /// decodes to garbage. `lookup` is this for a LuT of strings, as
+    /// generate and gen-world write.
+    ///
+    /// [`write_region_map`]: crate::write_region_map
+    pub fn lookup_as<T: DeserializeOwned>(&mut self, cell: Cell) -> Result<Option<(Cell, T)>> {
+        match self.lookup_index(cell)? {
+            Some((matched, lut_idx)) => Ok(Some((matched, self.resolve_as(lut_idx)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Reads a whole LuT into memory, decompressing it if `gzipped`,
+/// returning its entries' offsets into it along with it.
+fn read_lut_into_memory<R: Read + Seek>(
+    rdr: &mut R,
+    trailer: &Trailer,
+    gzipped: bool,
+) -> Result<(Vec<u64>, Vec<u8>)> {
+    let bytes = read_lut_bytes(rdr, trailer, gzipped)?;
+    let offsets = read_lut_offsets(rdr, trailer, 0..bytes.len() as u64)?;
+    // A serialized `Vec` starts with its u64 length, which holds
+    // whatever type the entries are.
+    let lut_len = match bytes.get(..8) {
+        Some(len) => u64::from_le_bytes(len.try_into().expect("8 bytes")),
+        None => return Err(Error::Corrupt("LuT is truncated".to_owned())),
+    };
+    if offsets.len() as u64 != lut_len {
+        return Err(Error::Corrupt(format!(
+            "{} LuT offsets for {lut_len} entries",
+            offsets.len()
+        )));
+    }
+    Ok((offsets, bytes))
+}
+
+/// Returns `cell`, or its parent at `resolution` if it's finer.
+fn coerce(cell: Cell, resolution: Option<Resolution>) -> Result<Cell> {
+    match resolution.map(u8::from) {
+        Some(res) if cell.res() > res => cell.to_parent(res).ok_or_else(|| {
+            Error::ResolutionMismatch(format!("no parent at res {res} for {cell:?}"))
+        }),
+        _ => Ok(cell),
+    }
+}
+
+/// Returns `lut_idx` if it's within a LuT of `len` entries.
+///
+/// Stored indices are checked as they're read, so a bad one is
+/// reported where it was found rather than when it's resolved.
+fn check_index(lut_idx: u32, len: usize) -> Result<u16> {
+    match u16::try_from(lut_idx) {
+        Ok(idx) if usize::from(idx) < len => Ok(idx),
+        _ => Err(Error::LutIndex { idx: lut_idx, len }),
+    }
+}
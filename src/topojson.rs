@@ -0,0 +1,218 @@
+//! Conversion of TopoJSON topologies to GeoJSON features.
+//!
+//! TopoJSON stores each boundary shared by neighbouring polygons once,
+//! as an arc that geometries refer to by index. Converting rebuilds
+//! each geometry's coordinates from its arcs, so the result can be
+//! tessellated like any GeoJSON.
+
+use crate::{Error, Result};
+use geojson::{feature::Id, Feature, FeatureCollection, Geometry, Value as GeoValue};
+use serde_json::Value;
+
+type Position = Vec<f64>;
+
+/// Converts a TopoJSON `topology` to a feature collection.
+///
+/// Each object becomes a feature, except that the members of a
+/// geometry collection object each become their own feature. Objects
+/// are taken in order of name, which fixes the features' indices.
+pub fn topojson_to_features(topology: &Value) -> Result<FeatureCollection> {
+    if topology["type"] != "Topology" {
+        return Err(bad("not a Topology"));
+    }
+    let transform = match topology.get("transform") {
+        Some(transform) => Some(Transform::parse(transform)?),
+        None => None,
+    };
+    let arcs = array(&topology["arcs"], "arcs")?
+        .iter()
+        .map(|arc| decode_arc(arc, transform.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    let topology_objects = topology["objects"]
+        .as_object()
+        .ok_or_else(|| bad("objects isn't an object"))?;
+    let mut objects: Vec<(&String, &Value)> = topology_objects.iter().collect();
+    objects.sort_by(|a, b| a.0.cmp(b.0));
+
+    let topology = Topology { arcs, transform };
+    let mut features = Vec::new();
+    for (_, object) in objects {
+        if object["type"] == "GeometryCollection" {
+            for member in array(&object["geometries"], "geometries")? {
+                features.push(topology.feature(member)?);
+            }
+        } else {
+            features.push(topology.feature(object)?);
+        }
+    }
+    Ok(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    })
+}
+
+/// Maps quantized positions back to coordinates.
+struct Transform {
+    scale: [f64; 2],
+    translate: [f64; 2],
+}
+
+impl Transform {
+    fn parse(transform: &Value) -> Result<Self> {
+        Ok(Self {
+            scale: pair(&transform["scale"], "scale")?,
+            translate: pair(&transform["translate"], "translate")?,
+        })
+    }
+
+    fn apply(&self, [x, y]: [f64; 2]) -> Position {
+        vec![
+            x * self.scale[0] + self.translate[0],
+            y * self.scale[1] + self.translate[1],
+        ]
+    }
+}
+
+/// A topology's decoded arcs, which its geometries are built from.
+struct Topology {
+    arcs: Vec<Vec<Position>>,
+    transform: Option<Transform>,
+}
+
+impl Topology {
+    fn feature(&self, object: &Value) -> Result<Feature> {
+        let id = match &object["id"] {
+            Value::String(id) => Some(Id::String(id.clone())),
+            Value::Number(id) => Some(Id::Number(id.clone())),
+            _ => None,
+        };
+        Ok(Feature {
+            bbox: None,
+            geometry: self.geometry(object)?.map(Geometry::new),
+            id,
+            properties: object["properties"].as_object().cloned(),
+            foreign_members: None,
+        })
+    }
+
+    /// Returns `object`'s geometry, or `None` for a null geometry.
+    fn geometry(&self, object: &Value) -> Result<Option<GeoValue>> {
+        let arcs = &object["arcs"];
+        let coordinates = &object["coordinates"];
+        let geometry = match &object["type"] {
+            Value::Null => return Ok(None),
+            Value::String(kind) => match kind.as_str() {
+                "Point" => GeoValue::Point(self.point(coordinates)?),
+                "MultiPoint" => GeoValue::MultiPoint(
+                    array(coordinates, "coordinates")?
+                        .iter()
+                        .map(|point| self.point(point))
+                        .collect::<Result<_>>()?,
+                ),
+                "LineString" => GeoValue::LineString(self.line(arcs)?),
+                "MultiLineString" => GeoValue::MultiLineString(self.lines(arcs)?),
+                "Polygon" => GeoValue::Polygon(self.lines(arcs)?),
+                "MultiPolygon" => GeoValue::MultiPolygon(
+                    array(arcs, "arcs")?
+                        .iter()
+                        .map(|polygon| self.lines(polygon))
+                        .collect::<Result<_>>()?,
+                ),
+                "GeometryCollection" => GeoValue::GeometryCollection(
+                    array(&object["geometries"], "geometries")?
+                        .iter()
+                        .filter_map(|member| self.geometry(member).transpose())
+                        .map(|geometry| geometry.map(Geometry::new))
+                        .collect::<Result<_>>()?,
+                ),
+                other => return Err(bad(&format!("unknown geometry type {other}"))),
+            },
+            other => return Err(bad(&format!("bad geometry type {other}"))),
+        };
+        Ok(Some(geometry))
+    }
+
+    /// Unlike arcs, point coordinates are quantized but not
+    /// delta-encoded.
+    fn point(&self, point: &Value) -> Result<Position> {
+        let position = pair(point, "point")?;
+        Ok(match &self.transform {
+            Some(transform) => transform.apply(position),
+            None => position.to_vec(),
+        })
+    }
+
+    /// Joins the arcs listed in `arcs` into one line or ring.
+    fn line(&self, arcs: &Value) -> Result<Vec<Position>> {
+        let mut line: Vec<Position> = Vec::new();
+        for idx in array(arcs, "arc indices")? {
+            let idx = idx
+                .as_i64()
+                .ok_or_else(|| bad("arc index isn't an integer"))?;
+            // A negative index is the one's complement of an arc to
+            // follow in reverse.
+            let (arc, reversed) = if idx < 0 { (!idx, true) } else { (idx, false) };
+            let arc = usize::try_from(arc)
+                .ok()
+                .and_then(|arc| self.arcs.get(arc))
+                .ok_or_else(|| bad(&format!("arc {idx} out of range")))?;
+            let mut points = arc.clone();
+            if reversed {
+                points.reverse();
+            }
+            // Each arc starts where the last one ended.
+            let skip = usize::from(!line.is_empty());
+            line.extend(points.into_iter().skip(skip));
+        }
+        Ok(line)
+    }
+
+    fn lines(&self, arcs: &Value) -> Result<Vec<Vec<Position>>> {
+        array(arcs, "arcs")?
+            .iter()
+            .map(|line| self.line(line))
+            .collect()
+    }
+}
+
+/// Decodes `arc`'s positions, which are delta-encoded when the
+/// topology is quantized.
+fn decode_arc(arc: &Value, transform: Option<&Transform>) -> Result<Vec<Position>> {
+    let positions = array(arc, "arc")?
+        .iter()
+        .map(|position| pair(position, "arc position"));
+    let Some(transform) = transform else {
+        return positions.map(|position| Ok(position?.to_vec())).collect();
+    };
+    let mut at = [0.0, 0.0];
+    positions
+        .map(|delta| {
+            let [dx, dy] = delta?;
+            at = [at[0] + dx, at[1] + dy];
+            Ok(transform.apply(at))
+        })
+        .collect()
+}
+
+fn array<'a>(value: &'a Value, what: &str) -> Result<&'a Vec<Value>> {
+    value
+        .as_array()
+        .ok_or_else(|| bad(&format!("{what} isn't an array")))
+}
+
+/// Reads the first two numbers of `value`, ignoring any further
+/// dimensions.
+fn pair(value: &Value, what: &str) -> Result<[f64; 2]> {
+    match array(value, what)?.as_slice() {
+        [x, y, ..] => match (x.as_f64(), y.as_f64()) {
+            (Some(x), Some(y)) => Ok([x, y]),
+            _ => Err(bad(&format!("{what} isn't numeric"))),
+        },
+        _ => Err(bad(&format!("{what} has fewer than two numbers"))),
+    }
+}
+
+fn bad(reason: &str) -> Error {
+    Error::TopoJson(reason.to_owned())
+}
@@ -0,0 +1,145 @@
+use crate::{
+    format::{
+        decode_lut, read_header, read_lut_bytes, read_lut_entry, read_lut_offsets, read_old_lut,
+        read_trailer, Header, Section, Trailer, OFFSET_TABLE_VERSION, TRAILER_LEN,
+    },
+    Error, MapFile, RegionMap, Result,
+};
+use hextree::disktree::DiskTree;
+use std::{
+    io::{Cursor, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Runs integrity checks on the map at `path`, after decompressing
+/// it if it's compressed.
+///
+/// The LuT check decodes entries as strings, as generate and gen-world
+/// write them, so it fails for a LuT of other values. A split LuT is
+/// only checked by the values check, which loads it to open the map.
+///
+/// Returns each check's name and outcome, in order. Each check depends
+/// on the ones before it, so the report stops at the first failure.
+pub fn verify<P: AsRef<Path>>(path: P) -> Vec<(&'static str, Result<()>)> {
+    let mut report = Vec::new();
+    verify_into(path.as_ref(), &mut report);
+    report
+}
+
+fn verify_into(path: &Path, report: &mut Vec<(&'static str, Result<()>)>) -> Option<()> {
+    fn check<T>(
+        report: &mut Vec<(&'static str, Result<()>)>,
+        name: &'static str,
+        result: Result<T>,
+    ) -> Option<T> {
+        match result {
+            Ok(val) => {
+                report.push((name, Ok(())));
+                Some(val)
+            }
+            Err(e) => {
+                report.push((name, Err(e)));
+                None
+            }
+        }
+    }
+
+    let (mut file, _) = check(report, "open", MapFile::open(path))?;
+    let (header, disktree_pos) = check(report, "header", {
+        (|| -> Result<(Header, u64)> {
+            let header = read_header(&mut file)?;
+            Ok((header, file.stream_position()?))
+        })()
+    })?;
+
+    // Files from before the offset table have only the LuT to check,
+    // which is read along with their trailer.
+    let (trailer, old_lut) = check(report, "lut offset", {
+        (|| -> Result<(Trailer, Option<Vec<u8>>)> {
+            let len = file.seek(SeekFrom::End(0))?;
+            let (trailer, old_lut) = if header.version < OFFSET_TABLE_VERSION {
+                let (trailer, bytes) = read_old_lut(&mut file, header.version)?;
+                (trailer, Some(bytes))
+            } else {
+                if len < disktree_pos + TRAILER_LEN {
+                    return Err(Error::Corrupt(format!(
+                        "file too short for trailer ({len} bytes)"
+                    )));
+                }
+                (read_trailer(&mut file)?, None)
+            };
+            if trailer.lut_pos < disktree_pos {
+                return Err(Error::Corrupt(format!(
+                    "lut offset {} is before the disktree at {disktree_pos}",
+                    trailer.lut_pos
+                )));
+            }
+            if trailer.lut_pos > len {
+                return Err(Error::Corrupt(format!(
+                    "lut offset {} exceeds file size {len}",
+                    trailer.lut_pos
+                )));
+            }
+            Ok((trailer, old_lut))
+        })()
+    })?;
+
+    check(report, "lut", {
+        (|| -> Result<()> {
+            if let Some(bytes) = old_lut {
+                let _lut: Vec<String> = decode_lut(&bytes)?;
+                return Ok(());
+            }
+            let bytes = read_lut_bytes(&mut file, &trailer, header.gzip_lut)?;
+            let lut: Vec<String> = decode_lut(&bytes)?;
+            // Every entry read through the offset table must match
+            // the one read in sequence. Offsets are into the file, or
+            // into the decompressed LuT if it's gzipped.
+            let lut_start = if header.gzip_lut { 0 } else { trailer.lut_pos };
+            let lut_span = lut_start..lut_start + bytes.len() as u64;
+            let offsets = read_lut_offsets(&mut file, &trailer, lut_span.clone())?;
+            if offsets.len() != lut.len() {
+                return Err(Error::Corrupt(format!(
+                    "{} LuT offsets for {} entries",
+                    offsets.len(),
+                    lut.len()
+                )));
+            }
+            let mut rdr = Cursor::new(&bytes);
+            let ends = offsets.iter().skip(1).chain([&lut_span.end]);
+            for (idx, ((&pos, &end), entry)) in offsets.iter().zip(ends).zip(lut).enumerate() {
+                let read: String = read_lut_entry(&mut rdr, pos - lut_start, end - lut_start)?;
+                if read != entry {
+                    return Err(Error::Corrupt(format!(
+                        "bad offset {pos} for LuT entry {idx}"
+                    )));
+                }
+            }
+            Ok(())
+        })()
+    })?;
+
+    check(
+        report,
+        "disktree",
+        DiskTree::from_reader(Section::new(file, disktree_pos))
+            .map(|_| ())
+            .map_err(Into::into),
+    )?;
+
+    // Walks the whole tree, so this is by far the slowest check.
+    check(report, "values", {
+        (|| -> Result<()> {
+            let mut region_map = RegionMap::open(path)?;
+            // Raw values are opaque without the closure that wrote
+            // them, so there's nothing to check.
+            if !region_map.header().has_lut {
+                return Ok(());
+            }
+            for entry in region_map.iter_indices()? {
+                entry?;
+            }
+            Ok(())
+        })()
+    })
+}
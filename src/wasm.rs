@@ -0,0 +1,39 @@
+//! WebAssembly bindings, built with the `wasm` feature for
+//! `wasm32-unknown-unknown`.
+//!
+//! There's no filesystem in the browser, so maps are opened from
+//! their bytes, e.g. as fetched:
+//!
+//! ```js
+//! const regions = RegionMap.open(new Uint8Array(await resp.arrayBuffer()));
+//! regions.lookup("8a2a1072b59ffff");
+//! ```
+
+use hextree::Cell;
+use std::{io::Cursor, rc::Rc};
+use wasm_bindgen::prelude::*;
+
+/// A region map read from an in-memory copy of a map file.
+#[wasm_bindgen(js_name = RegionMap)]
+pub struct WasmRegionMap {
+    inner: crate::RegionMap<Cursor<Rc<[u8]>>>,
+}
+
+#[wasm_bindgen(js_class = RegionMap)]
+impl WasmRegionMap {
+    /// Opens the map file in `bytes`.
+    pub fn open(bytes: Vec<u8>) -> Result<WasmRegionMap, JsError> {
+        // Both readers share the one copy of the file.
+        let bytes: Rc<[u8]> = bytes.into();
+        let inner = crate::RegionMap::from_readers(Cursor::new(bytes.clone()), Cursor::new(bytes))?;
+        Ok(Self { inner })
+    }
+
+    /// Returns the region containing `hex`, an h3 index in hex, or
+    /// `undefined` if the map has no entry for it.
+    pub fn lookup(&mut self, hex: &str) -> Result<Option<String>, JsError> {
+        let raw = u64::from_str_radix(hex.strip_prefix("0x").unwrap_or(hex), 16)?;
+        let cell = Cell::from_raw(raw)?;
+        Ok(self.inner.lookup(cell)?.map(|(_, val)| val))
+    }
+}
@@ -0,0 +1,103 @@
+use h3o::{CellIndex, LatLng, Resolution};
+use hextree::{Cell, HexTreeMap};
+use lwreg::{ConflictPolicy, RegionMap};
+use std::io::Cursor;
+
+#[test]
+fn descendants_of_a_compacted_cell_resolve_to_it() {
+    let parent = LatLng::new(37.77, -122.42)
+        .unwrap()
+        .to_cell(Resolution::Five);
+    let children: Vec<CellIndex> = parent.children(Resolution::Nine).collect();
+    let compacted = lwreg::compact_cells(children.clone()).unwrap();
+    assert_eq!(compacted, [parent]);
+
+    let mut map = HexTreeMap::new();
+    for cell in compacted {
+        map.insert(Cell::from_raw(cell.into()).unwrap(), 0_u16);
+    }
+    // A neighbor so that the parent isn't the only thing in the map.
+    let neighbor = parent.grid_disk::<Vec<_>>(1)[1];
+    map.insert(Cell::from_raw(neighbor.into()).unwrap(), 1_u16);
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Nine),
+        &map,
+        &["parent", "neighbor"],
+        false,
+        None,
+    )
+    .unwrap();
+    let mut region_map =
+        RegionMap::from_readers(Cursor::new(buf.clone()), Cursor::new(buf)).unwrap();
+
+    let stored = Cell::from_raw(parent.into()).unwrap();
+    let step = children.len() / 7;
+    let mut queries: Vec<CellIndex> = children.iter().step_by(step).copied().collect();
+    // Finer than the map's resolution, so coerced to res 9 first.
+    queries.push(children[0].center_child(Resolution::Twelve).unwrap());
+    for query in queries {
+        let found = region_map
+            .lookup(Cell::from_raw(query.into()).unwrap())
+            .unwrap();
+        assert_eq!(found, Some((stored, "parent".to_string())), "{query}");
+    }
+}
+
+#[test]
+fn capped_compaction_splits_only_coarser_cells() {
+    let coarse = LatLng::new(37.77, -122.42)
+        .unwrap()
+        .to_cell(Resolution::Four);
+    let fine = LatLng::new(40.71, -74.01)
+        .unwrap()
+        .to_cell(Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(Cell::from_raw(coarse.into()).unwrap(), 0_u16);
+    map.insert(Cell::from_raw(fine.into()).unwrap(), 1_u16);
+
+    let capped = lwreg::cap_compaction(&map, Resolution::Five).unwrap();
+    let mut cells: Vec<(u8, u16)> = capped
+        .iter()
+        .map(|(cell, &val)| (cell.res(), val))
+        .collect();
+    cells.sort_unstable();
+    let mut expected = vec![(5, 0); coarse.children_count(Resolution::Five) as usize];
+    expected.push((7, 1));
+    assert_eq!(cells, expected);
+}
+
+#[test]
+fn coarse_claims_conflict_with_finer_claims_under_them() {
+    let parent = LatLng::new(37.77, -122.42)
+        .unwrap()
+        .to_cell(Resolution::Five);
+    let fine = parent.center_child(Resolution::Seven).unwrap();
+    let cell = |cell: CellIndex| Cell::from_raw(cell.into()).unwrap();
+    let value = |map: &HexTreeMap<u16>, query: CellIndex| map.get(cell(query)).map(|(_, &val)| val);
+
+    // The parent is claimed around the earlier fine cell.
+    let mut map = HexTreeMap::new();
+    assert!(ConflictPolicy::First
+        .insert(&mut map, cell(fine), 0)
+        .is_empty());
+    let claims = ConflictPolicy::First.insert(&mut map, cell(parent), 1);
+    let claims: Vec<(u64, u16)> = claims
+        .into_iter()
+        .map(|(shared, val)| (shared.into_raw(), val))
+        .collect();
+    assert_eq!(claims, [(u64::from(fine), 0)]);
+    for child in parent.children(Resolution::Seven) {
+        let expected = if child == fine { 0 } else { 1 };
+        assert_eq!(value(&map, child), Some(expected), "{child}");
+    }
+
+    let mut map = HexTreeMap::new();
+    ConflictPolicy::Last.insert(&mut map, cell(fine), 0);
+    assert_eq!(
+        ConflictPolicy::Last.insert(&mut map, cell(parent), 1).len(),
+        1
+    );
+    assert_eq!(value(&map, fine), Some(1));
+}
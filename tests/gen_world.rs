@@ -0,0 +1,331 @@
+use geojson::{FeatureCollection, FeatureReader, GeoJson};
+use h3o::{geom::ContainmentMode, CellIndex, LatLng, Resolution};
+use lwreg::{ConflictPolicy, Phase, Progress, WorldOptions};
+use std::{
+    collections::HashSet,
+    io::Cursor,
+    sync::{atomic::AtomicBool, Mutex},
+};
+
+/// Three overlapping squares, so the result depends on the order
+/// features are inserted in.
+const WORLD: &str = r#"{
+  "type": "FeatureCollection",
+  "features": [
+    {
+      "type": "Feature",
+      "properties": { "name": "a" },
+      "geometry": {
+        "type": "Polygon",
+        "coordinates": [[[0, 0], [2, 0], [2, 2], [0, 2], [0, 0]]]
+      }
+    },
+    {
+      "type": "Feature",
+      "properties": { "name": "b" },
+      "geometry": {
+        "type": "Polygon",
+        "coordinates": [[[1, 1], [3, 1], [3, 3], [1, 3], [1, 1]]]
+      }
+    },
+    {
+      "type": "Feature",
+      "properties": { "name": "c" },
+      "geometry": {
+        "type": "Polygon",
+        "coordinates": [[[0.5, 0.5], [2.5, 0.5], [2.5, 2.5], [0.5, 2.5], [0.5, 0.5]]]
+      }
+    }
+  ]
+}"#;
+
+fn features() -> Vec<geojson::Feature> {
+    FeatureCollection::try_from(WORLD.parse::<GeoJson>().unwrap())
+        .unwrap()
+        .features
+}
+
+fn build(threads: usize) -> Vec<u8> {
+    let features = features();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    let world = pool
+        .install(|| {
+            lwreg::gen_world(
+                features.into_iter().map(Ok),
+                WorldOptions::new(Resolution::Five),
+                None,
+            )
+        })
+        .unwrap();
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Five),
+        &world.map,
+        &world.lut,
+        false,
+        None,
+    )
+    .unwrap();
+    buf
+}
+
+#[test]
+fn gen_world_is_independent_of_thread_count() {
+    assert_eq!(build(1), build(8));
+}
+
+#[test]
+fn gen_world_streams_features() {
+    let opts = WorldOptions {
+        property: Some("name"),
+        ..WorldOptions::new(Resolution::Five)
+    };
+    let streamed = FeatureReader::from_reader(WORLD.as_bytes()).features();
+    let world = lwreg::gen_world(streamed, opts, None).unwrap();
+    assert_eq!(world.lut, ["a", "b", "c"]);
+    assert_eq!(world.feature_count, 3);
+    let collected = lwreg::gen_world(features().into_iter().map(Ok), opts, None).unwrap();
+    assert!(world.map.iter().eq(collected.map.iter()));
+
+    // Unlike a feature that fails to tessellate, one that fails to
+    // parse ends the run even when keeping going.
+    let malformed = WORLD.replacen("Polygon", "Polygonal", 1);
+    let streamed = FeatureReader::from_reader(malformed.as_bytes()).features();
+    let opts = WorldOptions {
+        keep_going: true,
+        ..opts
+    };
+    let err = lwreg::gen_world(streamed, opts, None).unwrap_err();
+    assert!(matches!(err, lwreg::Error::GeoJson(_)), "{err}");
+}
+
+#[test]
+fn gen_world_reports_progress_and_cancels() {
+    let steps = Mutex::new(Vec::new());
+    let on_progress = |step: Progress| steps.lock().unwrap().push(step);
+    let opts = WorldOptions::new(Resolution::Five);
+    lwreg::gen_world(features().into_iter().map(Ok), opts, Some(&on_progress)).unwrap();
+    let steps = steps.into_inner().unwrap();
+    let inserted: Vec<usize> = steps
+        .iter()
+        .filter(|step| step.phase == Phase::Insert)
+        .map(|step| step.done)
+        .collect();
+    assert_eq!(inserted, [1, 2, 3]);
+    let tessellated = steps.iter().filter(|step| step.phase == Phase::Tessellate);
+    assert_eq!(tessellated.count(), 3);
+    assert!(steps.iter().all(|step| step.total == Some(3)));
+
+    let cancel = AtomicBool::new(true);
+    let opts = WorldOptions {
+        cancel: Some(&cancel),
+        ..opts
+    };
+    let err = lwreg::gen_world(features().into_iter().map(Ok), opts, None).unwrap_err();
+    assert!(matches!(err, lwreg::Error::Cancelled), "{err}");
+}
+
+#[test]
+fn gen_world_reports_overlaps() {
+    let opts = WorldOptions {
+        property: Some("name"),
+        ..WorldOptions::new(Resolution::Five)
+    };
+    let world = lwreg::gen_world(features().into_iter().map(Ok), opts, None).unwrap();
+    assert_eq!(world.lut, ["a", "b", "c"]);
+    // Every feature overlaps every earlier one.
+    let pairs: Vec<(u16, u16)> = world.overlaps.keys().copied().collect();
+    assert_eq!(pairs, [(0, 1), (0, 2), (1, 2)]);
+    assert!(world.overlaps.values().all(|&count| count > 0));
+
+    let opts = WorldOptions {
+        conflict: ConflictPolicy::Error,
+        ..opts
+    };
+    let err = lwreg::gen_world(features().into_iter().map(Ok), opts, None).unwrap_err();
+    assert!(matches!(err, lwreg::Error::Feature { idx: 1, .. }), "{err}");
+}
+
+#[test]
+fn failed_feature_releases_the_ones_after_it() {
+    // Far more features than fit in the window behind the first,
+    // which fails, so the rest have to give up rather than wait.
+    let mut failing = features()[0].clone();
+    failing.properties = None;
+    let features = std::iter::once(failing).chain(features().into_iter().cycle().take(1000));
+    let opts = WorldOptions {
+        property: Some("name"),
+        ..WorldOptions::new(Resolution::Two)
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let err = pool
+        .install(|| lwreg::gen_world(features.map(Ok), opts, None))
+        .unwrap_err();
+    assert!(matches!(err, lwreg::Error::Feature { idx: 0, .. }), "{err}");
+}
+
+#[test]
+fn feature_errors_name_the_feature() {
+    let features: Vec<geojson::Feature> = FeatureCollection::try_from(
+        r#"{
+          "type": "FeatureCollection",
+          "features": [
+            {
+              "type": "Feature",
+              "properties": { "code": "XY", "id": 7 },
+              "geometry": {
+                "type": "Polygon",
+                "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]
+              }
+            }
+          ]
+        }"#
+        .parse::<GeoJson>()
+        .unwrap(),
+    )
+    .unwrap()
+    .features;
+    let opts = WorldOptions {
+        property: Some("name"),
+        ..WorldOptions::new(Resolution::Five)
+    };
+    let err = lwreg::gen_world(features.clone().into_iter().map(Ok), opts, None).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "feature 0: code=XY, Polygon: missing property name"
+    );
+
+    let opts = WorldOptions {
+        id_property: Some("id"),
+        ..opts
+    };
+    let err = lwreg::gen_world(features.into_iter().map(Ok), opts, None).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "feature 0: id=7, Polygon: missing property name"
+    );
+}
+
+#[test]
+fn self_touching_multipolygon_compacts_without_nesting() {
+    // Two squares sharing an edge, and a third overlapping the first.
+    let features: Vec<geojson::Feature> = FeatureCollection::try_from(
+        r#"{
+          "type": "FeatureCollection",
+          "features": [
+            {
+              "type": "Feature",
+              "properties": { "name": "a" },
+              "geometry": {
+                "type": "MultiPolygon",
+                "coordinates": [
+                  [[[0, 0], [2, 0], [2, 2], [0, 2], [0, 0]]],
+                  [[[2, 0], [4, 0], [4, 2], [2, 2], [2, 0]]],
+                  [[[1, 1], [3, 1], [3, 3], [1, 3], [1, 1]]]
+                ]
+              }
+            }
+          ]
+        }"#
+        .parse::<GeoJson>()
+        .unwrap(),
+    )
+    .unwrap()
+    .features;
+    let feature = features.into_iter().next().unwrap();
+    let (_, _, cells) = lwreg::to_cells(
+        0,
+        feature,
+        Resolution::Five,
+        ContainmentMode::ContainsCentroid,
+    )
+    .unwrap();
+    // Overlapping polygons tessellate to some cells twice.
+    let expanded = lwreg::dedup_cells(cells).unwrap();
+    // Check the compacted cells themselves: a map can't hold a cell
+    // and its ancestor at once, so inserting them would hide nesting.
+    let compacted = lwreg::compact_cells(expanded.clone()).unwrap();
+    assert!(compacted.len() < expanded.len());
+    let set: HashSet<CellIndex> = compacted.iter().copied().collect();
+    for &cell in &compacted {
+        for res in 0..u8::from(cell.resolution()) {
+            let parent = cell.parent(Resolution::try_from(res).unwrap()).unwrap();
+            assert!(!set.contains(&parent), "{parent} holds {cell}");
+        }
+    }
+    // Compaction covers exactly the cells tessellation found, each
+    // once.
+    let covered: u64 = compacted
+        .iter()
+        .map(|cell| cell.children_count(Resolution::Five))
+        .sum();
+    assert_eq!(covered, expanded.len() as u64);
+}
+
+#[test]
+fn later_compacted_cells_conflict_with_finer_earlier_ones() {
+    // A dot claiming a single cell, then a field around it that
+    // compacts to cells coarser than the dot's.
+    let dot = LatLng::new(2.0, 2.0).unwrap().to_cell(Resolution::Five);
+    let center = LatLng::from(dot);
+    let (lat, lng) = (center.lat(), center.lng());
+    let (s, w, n, e) = (lat - 0.01, lng - 0.01, lat + 0.01, lng + 0.01);
+    let world = format!(
+        r#"{{
+          "type": "FeatureCollection",
+          "features": [
+            {{
+              "type": "Feature",
+              "properties": {{ "name": "dot" }},
+              "geometry": {{
+                "type": "Polygon",
+                "coordinates": [[[{w}, {s}], [{e}, {s}], [{e}, {n}], [{w}, {n}], [{w}, {s}]]]
+              }}
+            }},
+            {{
+              "type": "Feature",
+              "properties": {{ "name": "field" }},
+              "geometry": {{
+                "type": "Polygon",
+                "coordinates": [[[0, 0], [4, 0], [4, 4], [0, 4], [0, 0]]]
+              }}
+            }}
+          ]
+        }}"#
+    );
+    let features = FeatureCollection::try_from(world.parse::<GeoJson>().unwrap())
+        .unwrap()
+        .features;
+    let dot = hextree::Cell::from_raw(dot.into()).unwrap();
+    let value_at = |conflict| {
+        let opts = WorldOptions {
+            property: Some("name"),
+            conflict,
+            ..WorldOptions::new(Resolution::Five)
+        };
+        lwreg::gen_world(features.clone().into_iter().map(Ok), opts, None).map(|world| {
+            let (matched, &idx) = world.map.get(dot).unwrap();
+            (matched.res(), world.lut[usize::from(idx)].clone())
+        })
+    };
+
+    let (res, value) = value_at(ConflictPolicy::Last).unwrap();
+    assert!(
+        res < 5,
+        "the field should cover the dot with a coarser cell"
+    );
+    assert_eq!(value, "field");
+    assert_eq!(
+        value_at(ConflictPolicy::First).unwrap(),
+        (5, "dot".to_owned())
+    );
+    let err = value_at(ConflictPolicy::Error).unwrap_err();
+    assert!(matches!(err, lwreg::Error::Feature { idx: 1, .. }), "{err}");
+}
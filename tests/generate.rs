@@ -0,0 +1,396 @@
+use byteorder::{LittleEndian as LE, ReadBytesExt};
+use h3o::{LatLng, Resolution};
+use std::{
+    fs,
+    io::Cursor,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A scratch directory for one test, removed when dropped, so that it
+/// doesn't outlive a failed assertion.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory named after `name` and this process.
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("lwreg-{name}-{}", std::process::id()));
+        // Left over if an earlier run was killed.
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes a hex set of `n` cells around `lat`, `lng` to `path`.
+fn write_set(path: &Path, lat: f64, lng: f64) {
+    let center = LatLng::new(lat, lng).unwrap().to_cell(Resolution::Seven);
+    let cells: Vec<String> = center
+        .grid_disk::<Vec<_>>(2)
+        .into_iter()
+        .map(|cell| format!("{:x}", u64::from(cell)))
+        .collect();
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, cells.join("\n")).unwrap();
+}
+
+fn generate(dir: &Path, out: &str, opts: &[&str], sets: &[&str]) -> Vec<u8> {
+    let out = dir.join(out);
+    let status = Command::new(env!("CARGO_BIN_EXE_lwreg"))
+        .arg("-q")
+        .arg("generate")
+        .args(opts)
+        .arg(&out)
+        .args(sets.iter().map(|set| dir.join(set)))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fs::read(out).unwrap()
+}
+
+#[test]
+fn generate_is_independent_of_argument_order() {
+    let dir = TempDir::new("generate");
+    write_set(&dir.join("b.txt"), 10.0, 10.0);
+    write_set(&dir.join("a.txt"), 20.0, 20.0);
+    write_set(&dir.join("c.txt"), 30.0, 30.0);
+    // Same region name as a.txt, so only the path orders them.
+    write_set(&dir.join("more/a.txt"), 40.0, 40.0);
+
+    let opts = ["--allow-dup-names"];
+    let forward = generate(
+        &dir,
+        "forward",
+        &opts,
+        &["a.txt", "b.txt", "c.txt", "more/a.txt"],
+    );
+    let backward = generate(
+        &dir,
+        "backward",
+        &opts,
+        &["more/a.txt", "c.txt", "b.txt", "a.txt"],
+    );
+    let shuffled = generate(
+        &dir,
+        "shuffled",
+        &opts,
+        &["b.txt", "more/a.txt", "a.txt", "c.txt"],
+    );
+    assert_eq!(forward, backward);
+    assert_eq!(forward, shuffled);
+}
+
+#[test]
+fn resolution_auto_takes_the_inputs_resolution() {
+    let dir = TempDir::new("generate-auto");
+    write_set(&dir.join("a.txt"), 10.0, 10.0);
+    let coarse = LatLng::new(20.0, 20.0).unwrap().to_cell(Resolution::Five);
+    fs::write(dir.join("b.txt"), coarse.to_string()).unwrap();
+    let buf = generate(&dir, "uniform", &["--resolution", "auto"], &["a.txt"]);
+    let mixed = Command::new(env!("CARGO_BIN_EXE_lwreg"))
+        .arg("-q")
+        .arg("generate")
+        .args(["--resolution", "auto"])
+        .arg(dir.join("mixed"))
+        .arg(dir.join("a.txt"))
+        .arg(dir.join("b.txt"))
+        .status()
+        .unwrap();
+    let allowed = generate(
+        &dir,
+        "allowed",
+        &["--resolution", "auto", "--mixed-res"],
+        &["a.txt", "b.txt"],
+    );
+
+    let uniform = lwreg::RegionMap::from_readers(Cursor::new(buf.clone()), Cursor::new(buf));
+    assert_eq!(uniform.unwrap().resolution(), Some(Resolution::Seven));
+    assert!(!mixed.success());
+    let allowed =
+        lwreg::RegionMap::from_readers(Cursor::new(allowed.clone()), Cursor::new(allowed));
+    assert_eq!(allowed.unwrap().resolution(), Some(Resolution::Seven));
+}
+
+#[test]
+fn index_values_need_no_lut() {
+    let dir = TempDir::new("generate-index");
+    write_set(&dir.join("b.txt"), 10.0, 10.0);
+    write_set(&dir.join("a.txt"), 20.0, 20.0);
+    let buf = generate(
+        &dir,
+        "index",
+        &["--value-type", "index"],
+        &["b.txt", "a.txt"],
+    );
+
+    let mut region_map =
+        lwreg::RegionMap::from_readers(Cursor::new(buf.clone()), Cursor::new(buf)).unwrap();
+    assert!(!region_map.header().has_lut);
+    assert_eq!(region_map.lut_len(), 0);
+    // Sets are indexed in name order.
+    for (lat, lng, idx) in [(20.0, 20.0, 0), (10.0, 10.0, 1)] {
+        let cell = LatLng::new(lat, lng).unwrap().to_cell(Resolution::Seven);
+        let cell = hextree::Cell::from_raw(cell.into()).unwrap();
+        let (_, val) = region_map
+            .read_value(cell, |rdr| rdr.read_u32::<LE>())
+            .unwrap()
+            .unwrap();
+        assert_eq!(val, idx);
+    }
+}
+
+#[test]
+fn existing_output_needs_force() {
+    let dir = TempDir::new("generate-force");
+    write_set(&dir.join("a.txt"), 10.0, 10.0);
+    let first = generate(&dir, "map", &[], &["a.txt"]);
+    let status = Command::new(env!("CARGO_BIN_EXE_lwreg"))
+        .arg("-q")
+        .arg("generate")
+        .arg(dir.join("map"))
+        .arg(dir.join("a.txt"))
+        .status()
+        .unwrap();
+    let kept = fs::read(dir.join("map")).unwrap();
+    let forced = generate(&dir, "map", &["--force"], &["a.txt"]);
+    assert!(!status.success());
+    assert_eq!(first, kept);
+    assert_eq!(first, forced);
+}
+
+#[test]
+fn merge_sees_conflicts_under_compacted_cells() {
+    let dir = TempDir::new("merge");
+    let parent = LatLng::new(10.0, 10.0).unwrap().to_cell(Resolution::Five);
+    let fine = parent.center_child(Resolution::Seven).unwrap();
+    let sibling = parent
+        .children(Resolution::Seven)
+        .find(|&child| child != fine)
+        .unwrap();
+    fs::write(dir.join("coarse.txt"), parent.to_string()).unwrap();
+    fs::write(dir.join("fine.txt"), fine.to_string()).unwrap();
+    generate(&dir, "coarse.map", &[], &["coarse.txt"]);
+    generate(&dir, "fine.map", &[], &["fine.txt"]);
+
+    // The fine map comes first, so its claim is the earlier one.
+    let merge = |out: &str, conflict: &str| {
+        Command::new(env!("CARGO_BIN_EXE_lwreg"))
+            .arg("-q")
+            .arg("merge")
+            .args(["--conflict", conflict])
+            .arg(dir.join(out))
+            .arg(dir.join("fine.map"))
+            .arg(dir.join("coarse.map"))
+            .status()
+            .unwrap()
+            .success()
+    };
+    let first = merge("first.map", "first");
+    let error = merge("error.map", "error");
+    let mut merged = lwreg::RegionMap::open(dir.join("first.map")).unwrap();
+    let mut lookup = |cell: h3o::CellIndex| {
+        let cell = hextree::Cell::from_raw(cell.into()).unwrap();
+        merged.lookup(cell).unwrap().map(|(_, val)| val)
+    };
+    let (at_fine, at_sibling) = (lookup(fine), lookup(sibling));
+    assert!(first);
+    assert!(!error);
+    assert_eq!(at_fine.as_deref(), Some("fine"));
+    assert_eq!(at_sibling.as_deref(), Some("coarse"));
+}
+
+fn append(map: &Path, opts: &[&str], set: &Path) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_lwreg"))
+        .arg("-q")
+        .arg("append")
+        .args(opts)
+        .arg(map)
+        .arg(set)
+        .status()
+        .unwrap()
+        .success()
+}
+
+#[test]
+fn append_sees_conflicts_under_compacted_cells() {
+    let dir = TempDir::new("append");
+    let parent = LatLng::new(10.0, 10.0).unwrap().to_cell(Resolution::Five);
+    let fine = parent.center_child(Resolution::Seven).unwrap();
+    fs::write(dir.join("coarse.txt"), parent.to_string()).unwrap();
+    fs::write(dir.join("fine.txt"), fine.to_string()).unwrap();
+    let original = generate(&dir, "map", &[], &["fine.txt"]);
+
+    let refused = !append(
+        &dir.join("map"),
+        &["--conflict", "error"],
+        &dir.join("coarse.txt"),
+    );
+    let unchanged = fs::read(dir.join("map")).unwrap() == original;
+    let kept = append(
+        &dir.join("map"),
+        &["--conflict", "first"],
+        &dir.join("coarse.txt"),
+    );
+    let mut region_map = lwreg::RegionMap::open(dir.join("map")).unwrap();
+    let cell = hextree::Cell::from_raw(fine.into()).unwrap();
+    let (_, val) = region_map.lookup(cell).unwrap().unwrap();
+    assert!(refused);
+    assert!(unchanged);
+    assert!(kept);
+    assert_eq!(val, "fine");
+}
+
+#[test]
+fn append_no_compact_keeps_cells_at_the_maps_resolution() {
+    let dir = TempDir::new("append-expanded");
+    write_set(&dir.join("a.txt"), 10.0, 10.0);
+    let parent = LatLng::new(20.0, 20.0).unwrap().to_cell(Resolution::Five);
+    let children: Vec<String> = parent
+        .children(Resolution::Seven)
+        .map(|cell| cell.to_string())
+        .collect();
+    fs::write(dir.join("b.txt"), children.join("\n")).unwrap();
+    let expanded = ["--resolution", "7", "--no-compact"];
+    generate(&dir, "map", &expanded, &["a.txt"]);
+    let appended = append(&dir.join("map"), &["--no-compact"], &dir.join("b.txt"));
+
+    let mut region_map = lwreg::RegionMap::open(dir.join("map")).unwrap();
+    let resolutions: Vec<u8> = region_map
+        .iter_indices()
+        .unwrap()
+        .map(|entry| entry.unwrap().0.res())
+        .collect();
+    assert!(appended);
+    assert_eq!(resolutions.len(), 19 + 49);
+    assert!(resolutions.iter().all(|&res| res == 7));
+}
+
+#[test]
+fn append_keeps_a_split_lut_split() {
+    let dir = TempDir::new("append-split");
+    write_set(&dir.join("a.txt"), 10.0, 10.0);
+    write_set(&dir.join("b.txt"), 20.0, 20.0);
+    let status = Command::new(env!("CARGO_BIN_EXE_lwreg"))
+        .arg("-q")
+        .arg("generate")
+        .arg("--split")
+        .arg(dir.join("map"))
+        .arg(dir.join("a.txt"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let tree = dir.join("map.disktree");
+    let appended = append(&tree, &[], &dir.join("b.txt"));
+
+    let mut region_map = lwreg::RegionMap::open(&tree).unwrap();
+    let split = region_map.header().split_lut;
+    let lut = region_map.read_lut().unwrap();
+    assert!(appended);
+    assert!(split);
+    assert_eq!(lut, ["a", "b"]);
+}
+
+#[test]
+fn export_compact_dissolves_a_compacted_map() {
+    let dir = TempDir::new("export");
+    // A whole res-5 cell, which generate compacts, and one res-7 cell
+    // beside it that it can't, so the map mixes resolutions.
+    let parent = LatLng::new(10.0, 10.0).unwrap().to_cell(Resolution::Five);
+    let neighbor = parent.grid_disk::<Vec<_>>(1)[1];
+    let cells: Vec<String> = parent
+        .children(Resolution::Seven)
+        .chain(neighbor.center_child(Resolution::Seven))
+        .map(|cell| cell.to_string())
+        .collect();
+    fs::write(dir.join("a.txt"), cells.join("\n")).unwrap();
+    generate(&dir, "map", &[], &["a.txt"]);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_lwreg"))
+        .arg("-q")
+        .arg("export")
+        .arg("--compact")
+        .arg(dir.join("map"))
+        .arg("a")
+        .arg(dir.join("a.geojson"))
+        .status()
+        .unwrap();
+    let exported = fs::read_to_string(dir.join("a.geojson"));
+    assert!(status.success());
+    let exported = exported.unwrap().parse::<geojson::GeoJson>().unwrap();
+    let exported = geojson::FeatureCollection::try_from(exported).unwrap();
+    assert_eq!(exported.features.len(), 1);
+    let geometry = exported.features[0].geometry.as_ref().unwrap();
+    assert!(matches!(geometry.value, geojson::Value::MultiPolygon(_)));
+}
+
+/// Runs `lwreg diff a b`, returning its summary lines.
+fn diff(a: &Path, b: &Path) -> Vec<String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_lwreg"))
+        .arg("-q")
+        .arg("diff")
+        .arg(a)
+        .arg(b)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn diff_matches_compacted_and_expanded_cells_both_ways() {
+    let dir = TempDir::new("diff");
+    let parent = LatLng::new(10.0, 10.0).unwrap().to_cell(Resolution::Five);
+    let children: Vec<String> = parent
+        .children(Resolution::Seven)
+        .map(|cell| cell.to_string())
+        .collect();
+    // Same region name in each map, so only the cells differ.
+    fs::create_dir_all(dir.join("coarse")).unwrap();
+    fs::create_dir_all(dir.join("fine")).unwrap();
+    fs::create_dir_all(dir.join("holed")).unwrap();
+    fs::write(dir.join("coarse/r.txt"), parent.to_string()).unwrap();
+    fs::write(dir.join("fine/r.txt"), children.join("\n")).unwrap();
+    fs::write(dir.join("holed/r.txt"), children[1..].join("\n")).unwrap();
+    let expanded = ["--resolution", "7", "--no-compact"];
+    generate(&dir, "coarse.map", &[], &["coarse/r.txt"]);
+    generate(&dir, "fine.map", &expanded, &["fine/r.txt"]);
+    generate(&dir, "holed.map", &expanded, &["holed/r.txt"]);
+
+    let coarse = dir.join("coarse.map");
+    let fine = dir.join("fine.map");
+    let holed = dir.join("holed.map");
+    let coarse_fine = diff(&coarse, &fine);
+    let fine_coarse = diff(&fine, &coarse);
+    let coarse_holed = diff(&coarse, &holed);
+    let holed_coarse = diff(&holed, &coarse);
+    let same = ["only in a: 0", "only in b: 0", "changed:   0"];
+    assert_eq!(coarse_fine, same);
+    assert_eq!(fine_coarse, same);
+    assert_eq!(
+        coarse_holed,
+        ["only in a: 1", "only in b: 0", "changed:   0"]
+    );
+    assert_eq!(
+        holed_coarse,
+        ["only in a: 0", "only in b: 1", "changed:   0"]
+    );
+}
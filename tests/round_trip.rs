@@ -0,0 +1,482 @@
+use byteorder::{LittleEndian as LE, WriteBytesExt};
+use geojson::{FeatureCollection, GeoJson};
+use h3o::{LatLng, Resolution};
+use hextree::{Cell, HexTreeMap};
+use lwreg::{Compression, ConflictPolicy, Error, RegionMap, ValueWidth};
+use std::io::Cursor;
+
+fn cell_at(lat: f64, lng: f64, res: Resolution) -> Cell {
+    Cell::from_raw(LatLng::new(lat, lng).unwrap().to_cell(res).into()).unwrap()
+}
+
+fn open(buf: Vec<u8>) -> RegionMap<Cursor<Vec<u8>>> {
+    RegionMap::from_readers(Cursor::new(buf.clone()), Cursor::new(buf)).unwrap()
+}
+
+/// Two regions of one res-7 cell each, as `generate` would build from
+/// two sets.
+fn generate_style(gzip_lut: bool) {
+    let a = cell_at(37.77, -122.42, Resolution::Seven);
+    let b = cell_at(40.71, -74.01, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    assert!(ConflictPolicy::Last.insert(&mut map, a, 0).is_empty());
+    assert!(ConflictPolicy::Last.insert(&mut map, b, 1).is_empty());
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Seven),
+        &map,
+        &["sf", "nyc"],
+        gzip_lut,
+        None,
+    )
+    .unwrap();
+
+    let mut region_map = open(buf);
+    assert_eq!(region_map.resolution(), Some(Resolution::Seven));
+    assert_eq!(region_map.header().cell_count, 2);
+    assert_eq!(region_map.read_lut().unwrap(), ["sf", "nyc"]);
+    assert_eq!(region_map.lookup(a).unwrap(), Some((a, "sf".to_string())));
+    assert_eq!(region_map.lookup(b).unwrap(), Some((b, "nyc".to_string())));
+    let elsewhere = cell_at(51.51, -0.13, Resolution::Seven);
+    assert_eq!(region_map.lookup(elsewhere).unwrap(), None);
+}
+
+#[test]
+fn generate_style_map_round_trips() {
+    generate_style(false);
+}
+
+#[test]
+fn generate_style_map_with_gzipped_lut_round_trips() {
+    generate_style(true);
+}
+
+/// A map whose LuT holds `(name, population)` tuples rather than
+/// strings.
+fn typed_lut(gzip_lut: bool) {
+    let sf = cell_at(37.77, -122.42, Resolution::Seven);
+    let nyc = cell_at(40.71, -74.01, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(sf, 0);
+    map.insert(nyc, 1);
+    let lut = [
+        ("sf".to_string(), 808_437_u64),
+        ("nyc".to_string(), 8_258_035),
+    ];
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Seven),
+        &map,
+        &lut,
+        gzip_lut,
+        None,
+    )
+    .unwrap();
+
+    let mut region_map = open(buf);
+    assert_eq!(region_map.read_lut_as::<(String, u64)>().unwrap(), lut);
+    assert_eq!(
+        region_map.lookup_as::<(String, u64)>(nyc).unwrap(),
+        Some((nyc, lut[1].clone()))
+    );
+    assert_eq!(
+        region_map.lookup_as::<(String, u64)>(sf).unwrap(),
+        Some((sf, lut[0].clone()))
+    );
+}
+
+#[test]
+fn typed_lut_round_trips() {
+    typed_lut(false);
+}
+
+#[test]
+fn typed_gzipped_lut_round_trips() {
+    typed_lut(true);
+}
+
+/// A split map opened from its files, and from readers, where the LuT
+/// has to be loaded by hand.
+fn split_lut(gzip_lut: bool) {
+    let sf = cell_at(37.77, -122.42, Resolution::Seven);
+    let nyc = cell_at(40.71, -74.01, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(sf, 0);
+    map.insert(nyc, 1);
+    let (mut tree, mut lut) = (Vec::new(), Vec::new());
+    lwreg::write_split_region_map(
+        Cursor::new(&mut tree),
+        Cursor::new(&mut lut),
+        Some(Resolution::Seven),
+        &map,
+        &["sf", "nyc"],
+        gzip_lut,
+        None,
+    )
+    .unwrap();
+
+    let mut region_map = open(tree.clone());
+    assert!(region_map.header().split_lut);
+    assert!(matches!(region_map.lookup(sf), Err(Error::LutNotLoaded)));
+    region_map.load_lut(Cursor::new(&lut)).unwrap();
+    assert_eq!(region_map.read_lut().unwrap(), ["sf", "nyc"]);
+    assert_eq!(
+        region_map.lookup(nyc).unwrap(),
+        Some((nyc, "nyc".to_string()))
+    );
+
+    let dir = std::env::temp_dir().join(format!("lwreg-split-{}-{gzip_lut}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("map.disktree"), &tree).unwrap();
+    std::fs::write(dir.join("map.lut"), &lut).unwrap();
+    let mut region_map = RegionMap::open(dir.join("map.disktree")).unwrap();
+    assert_eq!(region_map.lookup(sf).unwrap(), Some((sf, "sf".to_string())));
+    std::fs::remove_file(dir.join("map.lut")).unwrap();
+    assert!(RegionMap::open(dir.join("map.disktree")).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn split_lut_round_trips() {
+    split_lut(false);
+}
+
+#[test]
+fn split_gzipped_lut_round_trips() {
+    split_lut(true);
+}
+
+#[test]
+fn lut_offset_past_end_of_file_is_reported() {
+    let a = cell_at(37.77, -122.42, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(a, 0);
+    let mut buf = Vec::new();
+    lwreg::write_region_map(Cursor::new(&mut buf), None, &map, &["sf"], false, None).unwrap();
+    // The LuT's position follows the trailer's CRC.
+    let len = buf.len();
+    let pos = len - 16;
+    buf[pos..pos + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+    let err = RegionMap::from_readers(Cursor::new(buf.clone()), Cursor::new(buf))
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "corrupt lwreg file: lut offset {} exceeds file size {len}",
+            u64::MAX
+        )
+    );
+}
+
+/// Lays out a map of `sf` and `nyc` at one res-7 cell each as files
+/// of format `version` were, returning it and the two cells.
+fn old_format(version: u8) -> (Vec<u8>, Cell, Cell) {
+    let sf = cell_at(37.77, -122.42, Resolution::Seven);
+    let nyc = cell_at(40.71, -74.01, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(sf, 0_u16);
+    map.insert(nyc, 1);
+    // Disktree offsets are relative to its own start, wherever that
+    // is in the file.
+    let mut tree = Vec::new();
+    map.to_disktree(Cursor::new(&mut tree), |wtr, &val| match version {
+        0 => wtr.write_u8(val as u8),
+        _ => wtr.write_u16::<LE>(val),
+    })
+    .unwrap();
+
+    let mut buf = Vec::new();
+    if version >= 2 {
+        buf.extend_from_slice(lwreg::MAGIC);
+        buf.push(version);
+    }
+    if version >= 6 {
+        // Values are LuT indices.
+        buf.push(1);
+    }
+    if version >= 3 {
+        buf.push(u8::from(Resolution::Seven));
+        buf.write_u64::<LE>(2).unwrap();
+    }
+    buf.extend_from_slice(&tree);
+    let lut_pos = buf.len() as u64;
+    let mut lut = bincode::serialize(&vec!["sf", "nyc"]).unwrap();
+    match version {
+        0 | 1 => {
+            buf.extend_from_slice(&lut);
+            buf.write_u64::<LE>(lut_pos | (u64::from(version) << 56))
+                .unwrap();
+        }
+        2 | 3 => {
+            buf.extend_from_slice(&lut);
+            buf.write_u64::<LE>(lut_pos).unwrap();
+        }
+        4 => {
+            buf.extend_from_slice(&lut);
+            buf.write_u32::<LE>(crc32fast::hash(&lut)).unwrap();
+            buf.write_u64::<LE>(lut_pos).unwrap();
+        }
+        _ => {
+            let offsets_pos = lut_pos + lut.len() as u64;
+            // Each entry is its u64 length and bytes.
+            lut.write_u64::<LE>(lut_pos + 8).unwrap();
+            lut.write_u64::<LE>(lut_pos + 8 + 8 + 2).unwrap();
+            buf.extend_from_slice(&lut);
+            buf.write_u32::<LE>(crc32fast::hash(&lut)).unwrap();
+            buf.write_u64::<LE>(lut_pos).unwrap();
+            buf.write_u64::<LE>(offsets_pos).unwrap();
+        }
+    }
+    (buf, sf, nyc)
+}
+
+#[test]
+fn old_formats_open() {
+    for version in 0..lwreg::FORMAT_VERSION {
+        let (buf, sf, nyc) = old_format(version);
+        let mut region_map = open(buf.clone());
+        let header = region_map.header().clone();
+        assert_eq!(header.version, version);
+        assert_eq!(header.cell_count, 2, "version {version}");
+        let resolution = (version >= 3).then_some(Resolution::Seven);
+        assert_eq!(header.resolution, resolution, "version {version}");
+        assert_eq!(header.h3o_version, "unknown");
+        assert_eq!(region_map.read_lut().unwrap(), ["sf", "nyc"]);
+        assert_eq!(region_map.lookup(sf).unwrap(), Some((sf, "sf".to_string())));
+        assert_eq!(
+            region_map.lookup(nyc).unwrap(),
+            Some((nyc, "nyc".to_string()))
+        );
+        let cells: Vec<_> = region_map.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(cells.len(), 2, "version {version}");
+
+        let path =
+            std::env::temp_dir().join(format!("lwreg-old-format-{version}-{}", std::process::id()));
+        std::fs::write(&path, buf).unwrap();
+        let report = lwreg::verify(&path);
+        std::fs::remove_file(&path).unwrap();
+        for (check, result) in report {
+            assert!(result.is_ok(), "version {version}: {check}: {result:?}");
+        }
+    }
+}
+
+#[test]
+fn future_format_is_rejected() {
+    let (mut buf, _, _) = old_format(6);
+    buf[lwreg::MAGIC.len()] = lwreg::FORMAT_VERSION + 1;
+    let err = RegionMap::from_readers(Cursor::new(buf.clone()), Cursor::new(buf))
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, Error::UnsupportedVersion(v) if v == lwreg::FORMAT_VERSION + 1),
+        "{err}"
+    );
+}
+
+#[test]
+fn value_widths_round_trip() {
+    let sf = cell_at(37.77, -122.42, Resolution::Seven);
+    let nyc = cell_at(40.71, -74.01, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(sf, 0);
+    map.insert(nyc, 1);
+    let lut = ["sf", "nyc"];
+    for width in [
+        None,
+        Some(ValueWidth::U8),
+        Some(ValueWidth::U16),
+        Some(ValueWidth::U32),
+    ] {
+        let mut buf = Vec::new();
+        lwreg::write_region_map(Cursor::new(&mut buf), None, &map, &lut, false, width).unwrap();
+        let mut region_map = open(buf);
+        // Two regions fit in a byte.
+        let expected = width.unwrap_or(ValueWidth::U8);
+        assert_eq!(region_map.header().value_width, expected);
+        assert_eq!(region_map.lookup(sf).unwrap().unwrap().1, "sf");
+        assert_eq!(region_map.lookup(nyc).unwrap().unwrap().1, "nyc");
+    }
+}
+
+#[test]
+fn u32_width_doesnt_lift_the_lut_limit() {
+    let lut: Vec<String> = (0..70_000).map(|idx| idx.to_string()).collect();
+    let mut map = HexTreeMap::new();
+    map.insert(cell_at(37.77, -122.42, Resolution::Seven), 0);
+    let mut buf = Vec::new();
+    let err = lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        None,
+        &map,
+        &lut,
+        false,
+        Some(ValueWidth::U32),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::TooManyValues {
+            max: 65536,
+            found: 70_000
+        }
+    ));
+}
+
+#[test]
+fn value_width_too_narrow_for_lut_is_an_error() {
+    let lut: Vec<String> = (0..300).map(|idx| idx.to_string()).collect();
+    let mut map = HexTreeMap::new();
+    map.insert(cell_at(37.77, -122.42, Resolution::Seven), 299);
+    let mut buf = Vec::new();
+    let err = lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        None,
+        &map,
+        &lut,
+        false,
+        Some(ValueWidth::U8),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::TooManyValues {
+            max: 256,
+            found: 300
+        }
+    ));
+}
+
+#[test]
+fn lookup_many_matches_lookup() {
+    let sf = cell_at(37.77, -122.42, Resolution::Seven);
+    let nyc = cell_at(40.71, -74.01, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(sf, 0);
+    map.insert(nyc, 1);
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Seven),
+        &map,
+        &["sf", "nyc"],
+        false,
+        None,
+    )
+    .unwrap();
+    let path = std::env::temp_dir().join(format!("lwreg-lookup-many-{}", std::process::id()));
+    std::fs::write(&path, &buf).unwrap();
+
+    let region_map = unsafe { RegionMap::open_mmap(&path) }.unwrap();
+    // A finer cell is coerced to the map's resolution.
+    let in_sf = cell_at(37.77, -122.42, Resolution::Ten);
+    let elsewhere = cell_at(51.51, -0.13, Resolution::Seven);
+    let cells = [sf, elsewhere, nyc, in_sf];
+    assert_eq!(
+        region_map.lookup_many(&cells).unwrap(),
+        [Some("sf"), None, Some("nyc"), Some("sf")]
+    );
+    assert_eq!(region_map.lookup_shared(in_sf).unwrap(), Some((sf, "sf")));
+    assert_eq!(region_map.lookup_shared(elsewhere).unwrap(), None);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn compressed_maps_open_transparently() {
+    let a = cell_at(37.77, -122.42, Resolution::Seven);
+    let mut map = HexTreeMap::new();
+    map.insert(a, 0);
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Seven),
+        &map,
+        &["sf"],
+        false,
+        None,
+    )
+    .unwrap();
+
+    for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+        let path = std::env::temp_dir().join(format!(
+            "lwreg-compressed-{compression:?}-{}",
+            std::process::id()
+        ));
+        let mut compressed = Vec::new();
+        compression.compress(&mut compressed, &buf).unwrap();
+        std::fs::write(&path, &compressed).unwrap();
+
+        let mut region_map = RegionMap::open(&path).unwrap();
+        assert_eq!(region_map.compression(), compression);
+        assert_eq!(region_map.lookup(a).unwrap(), Some((a, "sf".to_string())));
+        assert!(lwreg::verify(&path)
+            .iter()
+            .all(|(_, result)| result.is_ok()));
+        let mmapped = unsafe { RegionMap::open_mmap(&path) };
+        assert_eq!(
+            matches!(mmapped, Err(Error::Compressed)),
+            compression != Compression::None
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn gen_world_style_map_round_trips() {
+    let world = r#"{
+      "type": "FeatureCollection",
+      "features": [
+        {
+          "type": "Feature",
+          "properties": { "name": "west", "pop": 1 },
+          "geometry": {
+            "type": "Polygon",
+            "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]
+          }
+        },
+        {
+          "type": "Feature",
+          "properties": { "name": "east", "pop": 2 },
+          "geometry": {
+            "type": "Polygon",
+            "coordinates": [[[2, 0], [3, 0], [3, 1], [2, 1], [2, 0]]]
+          }
+        }
+      ]
+    }"#;
+    let features = FeatureCollection::try_from(world.parse::<GeoJson>().unwrap())
+        .unwrap()
+        .features;
+    let opts = lwreg::WorldOptions {
+        property: Some("name"),
+        ..lwreg::WorldOptions::new(Resolution::Six)
+    };
+    let world = lwreg::gen_world(features.into_iter().map(Ok), opts, None).unwrap();
+    assert_eq!(world.lut, ["west", "east"]);
+    assert!(world.empty.is_empty());
+    let mut buf = Vec::new();
+    lwreg::write_region_map(
+        Cursor::new(&mut buf),
+        Some(Resolution::Six),
+        &world.map,
+        &world.lut,
+        false,
+        None,
+    )
+    .unwrap();
+
+    let mut region_map = open(buf);
+    let lookup = |region_map: &mut RegionMap<_>, lat, lng| {
+        region_map
+            .lookup(cell_at(lat, lng, Resolution::Six))
+            .unwrap()
+            .map(|(_, val)| val)
+    };
+    assert_eq!(lookup(&mut region_map, 0.5, 0.5).as_deref(), Some("west"));
+    assert_eq!(lookup(&mut region_map, 0.5, 2.5).as_deref(), Some("east"));
+    assert_eq!(lookup(&mut region_map, 0.5, 1.5), None);
+}
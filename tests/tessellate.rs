@@ -0,0 +1,87 @@
+use geojson::Feature;
+use h3o::{geom::ContainmentMode, LatLng, Resolution};
+
+/// A 4° square with a 2° square hole in the middle.
+const OUTER: &str = "[[0, 0], [4, 0], [4, 4], [0, 4], [0, 0]]";
+const HOLE: &str = "[[1, 1], [3, 1], [3, 3], [1, 3], [1, 1]]";
+
+fn tessellate(geometry: &str) -> Vec<LatLng> {
+    let feature: Feature =
+        format!(r#"{{ "type": "Feature", "properties": {{}}, "geometry": {geometry} }}"#)
+            .parse()
+            .unwrap();
+    let (_, _, cells) = lwreg::to_cells(
+        0,
+        feature,
+        Resolution::Five,
+        ContainmentMode::ContainsCentroid,
+    )
+    .unwrap();
+    cells.into_iter().map(LatLng::from).collect()
+}
+
+fn assert_hole_is_empty(centers: &[LatLng]) {
+    let in_hole =
+        |center: &&LatLng| (1.0..3.0).contains(&center.lat()) && (1.0..3.0).contains(&center.lng());
+    assert!(!centers.is_empty());
+    assert_eq!(centers.iter().filter(in_hole).count(), 0);
+}
+
+#[test]
+fn polygon_holes_are_not_filled() {
+    let geometry = format!(r#"{{ "type": "Polygon", "coordinates": [{OUTER}, {HOLE}] }}"#);
+    assert_hole_is_empty(&tessellate(&geometry));
+}
+
+#[test]
+fn multipolygon_holes_are_not_filled() {
+    let island = "[[10, 10], [11, 10], [11, 11], [10, 11], [10, 10]]";
+    let geometry =
+        format!(r#"{{ "type": "MultiPolygon", "coordinates": [[{OUTER}, {HOLE}], [{island}]] }}"#);
+    assert_hole_is_empty(&tessellate(&geometry));
+}
+
+#[test]
+fn geometry_collection_members_are_all_tessellated() {
+    let island = "[[10, 10], [11, 10], [11, 11], [10, 11], [10, 10]]";
+    let geometry = format!(
+        r#"{{ "type": "GeometryCollection", "geometries": [
+              {{ "type": "Polygon", "coordinates": [{OUTER}, {HOLE}] }},
+              {{ "type": "Polygon", "coordinates": [{island}] }}
+           ] }}"#
+    );
+    let centers = tessellate(&geometry);
+    assert_hole_is_empty(&centers);
+    let on_island = |center: &&LatLng| (10.0..11.0).contains(&center.lat());
+    assert!(centers.iter().filter(on_island).count() > 0);
+    let square = format!(r#"{{ "type": "Polygon", "coordinates": [{OUTER}, {HOLE}] }}"#);
+    let island = format!(r#"{{ "type": "Polygon", "coordinates": [{island}] }}"#);
+    assert_eq!(
+        centers.len(),
+        tessellate(&square).len() + tessellate(&island).len()
+    );
+}
+
+#[test]
+fn region_contains_checks_exact_geometry() {
+    let feature: Feature = format!(
+        r#"{{ "type": "Feature", "properties": {{ "name": "ring" }},
+              "geometry": {{ "type": "Polygon", "coordinates": [{OUTER}, {HOLE}] }} }}"#
+    )
+    .parse()
+    .unwrap();
+    let contains = |value: &str, lat: f64, lng: f64| {
+        let features = [Ok(feature.clone())];
+        lwreg::region_contains(
+            features,
+            Some("name"),
+            value,
+            LatLng::new(lat, lng).unwrap(),
+        )
+        .unwrap()
+    };
+    assert!(contains("ring", 0.5, 0.5));
+    assert!(!contains("ring", 2.0, 2.0), "in the hole");
+    assert!(!contains("ring", 5.0, 5.0), "outside");
+    assert!(!contains("other", 0.5, 0.5), "another region");
+}
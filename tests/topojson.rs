@@ -0,0 +1,55 @@
+use geojson::Value;
+
+#[test]
+fn shared_arcs_are_stitched_into_rings() {
+    // Two unit squares sharing the arc along x = 1, quantized to a
+    // 0.5° grid and delta-encoded.
+    let topology: serde_json::Value = serde_json::from_str(
+        r#"{
+          "type": "Topology",
+          "transform": { "scale": [0.5, 0.5], "translate": [10, 20] },
+          "objects": {
+            "west": { "type": "Polygon", "arcs": [[0, 1]], "properties": { "name": "west" } },
+            "east": { "type": "Polygon", "arcs": [[-1, 2]], "id": 7 }
+          },
+          "arcs": [
+            [[2, 0], [0, 2]],
+            [[2, 2], [-2, 0], [0, -2], [2, 0]],
+            [[2, 0], [2, 0], [0, 2], [-2, 0]]
+          ]
+        }"#,
+    )
+    .unwrap();
+    let features = lwreg::topojson_to_features(&topology).unwrap().features;
+    assert_eq!(features.len(), 2);
+
+    // Objects come in order of name.
+    let (east, west) = (&features[0], &features[1]);
+    assert_eq!(west.property("name").unwrap(), "west");
+    assert_eq!(east.id, Some(geojson::feature::Id::Number(7.into())));
+
+    let ring = |feature: &geojson::Feature| match &feature.geometry.as_ref().unwrap().value {
+        Value::Polygon(rings) => rings[0].clone(),
+        other => panic!("expected a polygon, found {other:?}"),
+    };
+    assert_eq!(
+        ring(west),
+        [
+            [11.0, 20.0],
+            [11.0, 21.0],
+            [10.0, 21.0],
+            [10.0, 20.0],
+            [11.0, 20.0]
+        ]
+    );
+    assert_eq!(
+        ring(east),
+        [
+            [11.0, 21.0],
+            [11.0, 20.0],
+            [12.0, 20.0],
+            [12.0, 21.0],
+            [11.0, 21.0]
+        ]
+    );
+}